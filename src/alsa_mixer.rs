@@ -0,0 +1,134 @@
+#[cfg(feature = "alsa")]
+use alsa::{
+    card,
+    mixer::{Selem, SelemChannelId},
+    Mixer,
+};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "alsa")]
+use crate::pulse::{AudioError, Mute};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlsaMuteControl {
+    All,
+    Selected,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlsaSettings {
+    pub cards: Vec<String>,
+    pub mute_control: AlsaMuteControl,
+    pub selected_controls: Vec<String>,
+}
+impl Default for AlsaSettings {
+    fn default() -> Self {
+        Self {
+            cards: Vec::new(),
+            mute_control: AlsaMuteControl::All,
+            selected_controls: vec![String::from("Capture")],
+        }
+    }
+}
+
+/// Mutes capture switches of the ALSA mixer directly, for systems without a sound server.
+/// Mixers are opened on each operation, so cards that are plugged in later are picked up.
+#[cfg(feature = "alsa")]
+pub struct AlsaControl {
+    settings: AlsaSettings,
+}
+
+#[cfg(feature = "alsa")]
+impl AlsaControl {
+    pub fn new(settings: AlsaSettings) -> Self {
+        Self { settings }
+    }
+
+    /// Returns the configured card names, or all cards if none are configured.
+    fn card_names(&self) -> Vec<String> {
+        if !self.settings.cards.is_empty() {
+            return self.settings.cards.clone();
+        }
+        let mut names = Vec::new();
+        for card in card::Iter::new() {
+            match card {
+                Ok(card) => names.push(format!("hw:{}", card.get_index())),
+                Err(err) => println!("Could not list sound cards: {}", err),
+            }
+        }
+        names
+    }
+
+    fn is_selected(&self, selem: &Selem) -> bool {
+        if !selem.has_capture_switch() {
+            return false;
+        }
+        match self.settings.mute_control {
+            AlsaMuteControl::All => true,
+            AlsaMuteControl::Selected => match selem.get_id().get_name() {
+                Ok(name) => self.settings.selected_controls.iter().any(|c| c == name),
+                Err(_) => false,
+            },
+        }
+    }
+
+    /// Calls `f` for each selected capture control on all cards. Fails if there are none.
+    fn for_each_control<F: FnMut(&Selem)>(&self, mut f: F) -> Result<(), AudioError> {
+        let mut found = false;
+        for card_name in self.card_names() {
+            let mixer = match Mixer::new(&card_name, false) {
+                Ok(mixer) => mixer,
+                Err(err) => {
+                    println!("Could not open mixer {}: {}", card_name, err);
+                    continue;
+                },
+            };
+            for elem in mixer.iter() {
+                if let Some(selem) = Selem::new(elem) {
+                    if self.is_selected(&selem) {
+                        found = true;
+                        f(&selem);
+                    }
+                }
+            }
+        }
+        if !found {
+            return Err(AudioError::Failed(String::from(
+                "Could not find any capture controls",
+            )));
+        }
+        Ok(())
+    }
+}
+#[cfg(feature = "alsa")]
+impl Mute for AlsaControl {
+    fn is_muted(&mut self) -> Result<Option<bool>, AudioError> {
+        let mut muted = true;
+        self.for_each_control(|selem| {
+            for channel in SelemChannelId::all() {
+                if !selem.has_capture_channel(*channel) {
+                    continue;
+                }
+                if let Ok(switch) = selem.get_capture_switch(*channel) {
+                    muted = muted && switch == 0;
+                }
+            }
+        })?;
+        Ok(Some(muted))
+    }
+
+    fn set_muted(&mut self, muted: bool) -> Result<(), AudioError> {
+        let switch = if muted { 0 } else { 1 };
+        let mut result = Ok(());
+        self.for_each_control(|selem| {
+            if let Err(err) = selem.set_capture_switch_all(switch) {
+                result = Err(AudioError::Failed(format!(
+                    "Could not set capture switch: {}",
+                    err
+                )));
+            }
+        })?;
+        result
+    }
+}
@@ -0,0 +1,1385 @@
+use std::time::{Duration, Instant};
+
+use crate::device::{Color, DeviceEvent, LedEffect, LedState};
+use crate::gestures::{GestureAction, GestureSettings, Gestures};
+use crate::muteme::{ControlStatus, MuteMeSettings, OperationMode};
+
+/// Delay before the light flashes and the audio state is applied after a change.
+pub const TRANSITION_DELAY: Duration = Duration::from_millis(100);
+/// Maximum time without events, after which the light is refreshed.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Input of the controller, from the button, the audio thread, or a remote command.
+#[derive(Debug)]
+pub enum ControlEvent {
+    Device(DeviceEvent),
+    MuteStatusChanged(bool),
+    AudioErrorChanged(bool),
+    SetMuted(bool),
+    Toggle,
+    SetDeafened(bool),
+    ToggleDeafen,
+    SetDeviceConnected(bool),
+    SetColor(bool, Color),
+    SetMode(OperationMode),
+    Continue,
+    Keepalive,
+}
+/// Output of the controller, to be carried out by the caller.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    SetLight(LedState),
+    SetMuted(bool),
+    SetDeafened(bool),
+    RunCommand(String),
+    /// Requests a `ControlEvent::Continue` after the given delay.
+    ScheduleContinue(Duration),
+}
+
+/// Mute state machine of a group of buttons. It does not read the clock itself, so that
+/// timing can be tested.
+pub struct Controller {
+    settings: MuteMeSettings,
+    gesture_settings: GestureSettings,
+    gestures: Gestures,
+    is_muted: bool,
+    is_deafened: bool,
+    // Restored when undeafening.
+    muted_before_deafen: bool,
+    transition: bool,
+    // Mute state last applied to the audio devices, or reported by them.
+    audio_muted: Option<bool>,
+    device_connected: bool,
+    // Whether the last audio operation failed.
+    audio_error: bool,
+    // Start of the current touch, while the button is touched.
+    touched_at: Option<Instant>,
+    // Start of the last touch of the current tap sequence.
+    last_touch: Option<Instant>,
+    // Taps of the current sequence, which are waiting for more taps.
+    taps: u8,
+    muted_before_touch: bool,
+    // Whether the hold action of the current touch has started.
+    holding: bool,
+    // Whether the current touch has been detected as a long press.
+    long_press: bool,
+    // Start of the unmuted state, if auto mute is enabled.
+    unmuted_since: Option<Instant>,
+}
+
+impl Controller {
+    pub fn new(settings: MuteMeSettings, mut gesture_settings: GestureSettings) -> Self {
+        if gesture_settings.long_press.is_none() {
+            gesture_settings.long_press = settings.legacy_long_press();
+            if gesture_settings.long_press.is_some() {
+                println!("long_press_action is deprecated, use long_press in [gestures] instead");
+            }
+        }
+        let gestures = Gestures::new(
+            &gesture_settings,
+            &settings.operation_mode,
+            settings.deafen_on_double_tap,
+        );
+        Self {
+            settings,
+            gesture_settings,
+            gestures,
+            is_muted: false,
+            is_deafened: false,
+            muted_before_deafen: false,
+            transition: false,
+            audio_muted: None,
+            device_connected: false,
+            audio_error: false,
+            touched_at: None,
+            last_touch: None,
+            taps: 0,
+            muted_before_touch: false,
+            holding: false,
+            long_press: false,
+            unmuted_since: None,
+        }
+    }
+
+    pub fn status(&self) -> ControlStatus {
+        ControlStatus {
+            muted: self.is_muted,
+            deafened: self.is_deafened,
+            operation_mode: self.settings.operation_mode.clone(),
+            device_connected: self.device_connected,
+        }
+    }
+
+    pub fn on_event(&mut self, event: ControlEvent, now: Instant) -> Vec<Action> {
+        let mut actions = Vec::new();
+        match event {
+            ControlEvent::MuteStatusChanged(state) => {
+                self.set_muted(state);
+                self.audio_muted = Some(state);
+            },
+            ControlEvent::SetMuted(state) => {
+                self.set_muted(state);
+            },
+            ControlEvent::AudioErrorChanged(failed) => {
+                self.audio_error = failed;
+                self.transition = false;
+            },
+            ControlEvent::Toggle => {
+                self.is_muted = !self.is_muted;
+                self.transition = false;
+            },
+            ControlEvent::SetDeafened(state) => {
+                if state != self.is_deafened {
+                    self.set_deafened(state, &mut actions);
+                }
+            },
+            ControlEvent::ToggleDeafen => {
+                self.set_deafened(!self.is_deafened, &mut actions);
+            },
+            ControlEvent::SetDeviceConnected(connected) => {
+                self.device_connected = connected;
+            },
+            ControlEvent::SetColor(mute_state, color) => {
+                if mute_state {
+                    self.settings.muted_color = color;
+                } else {
+                    self.settings.unmuted_color = color;
+                }
+                self.transition = false;
+            },
+            ControlEvent::SetMode(new_mode) => {
+                self.set_mode(new_mode);
+            },
+            ControlEvent::Device(DeviceEvent::Touch) => {
+                println!("Touch event");
+                self.on_touch(now, &mut actions);
+            },
+            ControlEvent::Device(DeviceEvent::Release) => {
+                println!("Release event");
+                self.on_release(now, &mut actions);
+            },
+            ControlEvent::Continue => {
+                self.check_hold(now, &mut actions);
+                self.check_long_press(now, &mut actions);
+                self.check_taps(now, &mut actions);
+            },
+            ControlEvent::Keepalive => {
+                println!("Sending keepalive");
+                self.transition = false;
+            },
+        }
+        self.check_deafen(&mut actions);
+        self.check_auto_mute(now, &mut actions);
+        self.update_light(now, &mut actions);
+        actions
+    }
+
+    fn set_muted(&mut self, state: bool) {
+        if state != self.is_muted {
+            self.is_muted = state;
+            self.transition = false;
+        }
+    }
+
+    /// Mutes the speakers, along with the microphone, or unmutes them and restores the
+    /// previous microphone state.
+    fn set_deafened(&mut self, deafened: bool, actions: &mut Vec<Action>) {
+        if deafened && !self.is_deafened {
+            self.muted_before_deafen = self.is_muted;
+            self.is_muted = true;
+        } else if !deafened && self.is_deafened {
+            self.is_muted = self.muted_before_deafen;
+        }
+        self.is_deafened = deafened;
+        self.transition = false;
+        actions.push(Action::SetDeafened(deafened));
+    }
+
+    /// Ends deafen once the microphone is unmuted by any other means, since the speakers
+    /// should not stay muted while the user is live.
+    fn check_deafen(&mut self, actions: &mut Vec<Action>) {
+        if self.is_deafened && !self.is_muted {
+            println!("Unmuted while deafened, ending deafen");
+            self.is_deafened = false;
+            actions.push(Action::SetDeafened(false));
+        }
+    }
+
+    fn set_mode(&mut self, mode: OperationMode) {
+        self.gestures = Gestures::new(
+            &self.gesture_settings,
+            &mode,
+            self.settings.deafen_on_double_tap,
+        );
+        // Push to mute is live by default, all other modes start muted.
+        self.is_muted = mode != OperationMode::PushToMute;
+        self.settings.operation_mode = mode;
+        self.taps = 0;
+        self.last_touch = None;
+        self.transition = false;
+    }
+
+    /// Switches to the next operation mode in the list of profiles.
+    fn switch_profile(&mut self) {
+        let profiles = &self.gesture_settings.profiles;
+        if profiles.is_empty() {
+            return;
+        }
+        let next = match profiles
+            .iter()
+            .position(|mode| *mode == self.settings.operation_mode)
+        {
+            Some(index) => profiles[(index + 1) % profiles.len()].clone(),
+            None => profiles[0].clone(),
+        };
+        println!("Switched to {} mode", next);
+        self.set_mode(next);
+    }
+
+    fn run_action(&mut self, action: GestureAction, actions: &mut Vec<Action>) {
+        match action {
+            GestureAction::None => {},
+            GestureAction::Toggle => {
+                self.is_muted = !self.is_muted;
+                self.transition = false;
+            },
+            GestureAction::Mute | GestureAction::MomentaryMute => self.set_muted(true),
+            GestureAction::Unmute | GestureAction::MomentaryUnmute => self.set_muted(false),
+            GestureAction::Deafen => self.set_deafened(!self.is_deafened, actions),
+            GestureAction::SwitchProfile => self.switch_profile(),
+            GestureAction::Command(command) => actions.push(Action::RunCommand(command)),
+        }
+    }
+
+    /// Runs the action for a sequence of taps. While deafened, a single tap ends deafen
+    /// instead, like in voice chat apps.
+    fn run_taps(&mut self, count: u8, actions: &mut Vec<Action>) {
+        let action = self.gestures.taps(count).clone();
+        println!("{} tap(s) detected", count);
+        if count == 1 && self.is_deafened && action != GestureAction::None {
+            self.set_deafened(false, actions);
+        } else {
+            self.run_action(action, actions);
+        }
+        self.taps = 0;
+    }
+
+    fn on_touch(&mut self, now: Instant, actions: &mut Vec<Action>) {
+        // Taps that have not been followed by another one in time are complete.
+        self.check_taps(now, actions);
+        self.touched_at = Some(now);
+        self.last_touch = Some(now);
+        self.muted_before_touch = self.is_muted;
+        self.holding = false;
+        self.long_press = false;
+        if self.gestures.long_press != GestureAction::None {
+            actions.push(Action::ScheduleContinue(self.long_press_duration()));
+        }
+        let hold_delay = self.hold_delay();
+        if hold_delay > Duration::from_millis(0) {
+            actions.push(Action::ScheduleContinue(hold_delay));
+        }
+        self.check_hold(now, actions);
+    }
+
+    fn on_release(&mut self, now: Instant, actions: &mut Vec<Action>) {
+        self.check_hold(now, actions);
+        self.check_long_press(now, actions);
+        let held = self
+            .touched_at
+            .take()
+            .map_or(Duration::from_millis(0), |t| now.duration_since(t));
+        if self.long_press {
+            // The release belongs to the long press.
+            self.long_press = false;
+            self.transition = false;
+            return;
+        }
+        let mut count = self.taps + 1;
+        if self.taps > 0 && held >= self.double_tap_duration_2() {
+            // Held too long to continue the sequence, so it starts over.
+            println!("Release on {}. touch after {:?}", count, held);
+            self.run_taps(self.taps, actions);
+            count = 1;
+        }
+        let max_taps = self.gestures.max_taps();
+        let completes_taps = count >= 2 && count >= max_taps;
+        if self.holding && !(completes_taps && *self.gestures.taps(count) != GestureAction::None) {
+            // A completed double or triple tap replaces the end of the hold.
+            match self.gestures.hold {
+                GestureAction::MomentaryUnmute => self.set_muted(true),
+                GestureAction::MomentaryMute => self.set_muted(false),
+                _ => {},
+            }
+        }
+        let double_tap_duration_1 = self.double_tap_duration_1();
+        if count >= max_taps || held >= double_tap_duration_1 {
+            self.run_taps(count, actions);
+            self.last_touch = None;
+        } else {
+            // Waits whether another tap follows.
+            self.taps = count;
+            actions.push(Action::ScheduleContinue(double_tap_duration_1 - held));
+        }
+    }
+
+    /// Mutes once the microphone has been unmuted for longer than `auto_mute_after`. When the
+    /// microphone is unmuted, schedules checks for the start of the warning and for the deadline.
+    fn check_auto_mute(&mut self, now: Instant, actions: &mut Vec<Action>) {
+        let auto_mute_after = match self.auto_mute_after() {
+            Some(duration) => duration,
+            None => return,
+        };
+        if self.is_muted {
+            self.unmuted_since = None;
+            return;
+        }
+        let unmuted_since = match self.unmuted_since {
+            Some(t) => t,
+            None => {
+                self.unmuted_since = Some(now);
+                let warning_start = auto_mute_after.saturating_sub(self.auto_mute_warning());
+                actions.push(Action::ScheduleContinue(warning_start));
+                actions.push(Action::ScheduleContinue(auto_mute_after));
+                now
+            },
+        };
+        if now.duration_since(unmuted_since) >= auto_mute_after {
+            println!("Muting after {:?} unmuted", auto_mute_after);
+            self.set_muted(true);
+            self.unmuted_since = None;
+        }
+    }
+
+    fn auto_mute_deadline(&self) -> Option<Instant> {
+        match (self.unmuted_since, self.auto_mute_after()) {
+            (Some(t), Some(duration)) => Some(t + duration),
+            _ => None,
+        }
+    }
+
+    fn auto_mute_after(&self) -> Option<Duration> {
+        self.settings
+            .auto_mute_after
+            .map(|minutes| Duration::from_secs(u64::from(minutes) * 60))
+    }
+
+    fn auto_mute_warning(&self) -> Duration {
+        Duration::from_secs(self.settings.auto_mute_warning.into())
+    }
+
+    /// Starts the hold action once the button has been held for the delay of the action.
+    fn check_hold(&mut self, now: Instant, actions: &mut Vec<Action>) {
+        if self.holding || self.long_press {
+            return;
+        }
+        if let Some(t) = self.touched_at {
+            if now.duration_since(t) >= self.hold_delay() {
+                self.holding = true;
+                self.run_action(self.gestures.hold.clone(), actions);
+            }
+        }
+    }
+
+    /// Returns how long the button has to be held before the hold action starts. Only muting
+    /// is delayed, to ignore accidental touches.
+    fn hold_delay(&self) -> Duration {
+        match self.gestures.hold {
+            GestureAction::MomentaryMute => {
+                Duration::from_millis(self.settings.mute_hold_delay.into())
+            },
+            _ => Duration::from_millis(0),
+        }
+    }
+
+    /// Runs the waiting taps once no further tap can follow.
+    fn check_taps(&mut self, now: Instant, actions: &mut Vec<Action>) {
+        if self.taps == 0 || self.touched_at.is_some() {
+            return;
+        }
+        if let Some(t) = self.last_touch {
+            if now.duration_since(t) >= self.double_tap_duration_1() {
+                self.run_taps(self.taps, actions);
+                self.last_touch = None;
+            }
+        }
+    }
+
+    /// Runs the long press action once the button has been held long enough. The mute state
+    /// from before the touch is restored first.
+    fn check_long_press(&mut self, now: Instant, actions: &mut Vec<Action>) {
+        if self.long_press || self.gestures.long_press == GestureAction::None {
+            return;
+        }
+        let held = match self.touched_at {
+            Some(t) => now.duration_since(t),
+            None => return,
+        };
+        if held < self.long_press_duration() {
+            return;
+        }
+        println!("Long press detected after {:?}", held);
+        self.long_press = true;
+        self.taps = 0;
+        self.last_touch = None;
+        self.set_muted(self.muted_before_touch);
+        self.transition = false;
+        self.run_action(self.gestures.long_press.clone(), actions);
+    }
+
+    fn long_press_duration(&self) -> Duration {
+        Duration::from_millis(self.settings.long_press_duration.into())
+    }
+
+    fn double_tap_duration_1(&self) -> Duration {
+        Duration::from_millis(self.settings.double_tap_duration_1.into())
+    }
+
+    fn double_tap_duration_2(&self) -> Duration {
+        Duration::from_millis(self.settings.double_tap_duration_2.into())
+    }
+
+    /// Shows the new state on the light right away, and switches to the effect of the state
+    /// once the transition delay has passed, when a changed state is also applied to the audio
+    /// devices. Pulses shortly before auto mute. Audio errors take precedence, since the state
+    /// may not be accurate.
+    fn update_light(&mut self, now: Instant, actions: &mut Vec<Action>) {
+        let (color, state_effect) = if self.long_press {
+            (self.settings.long_press_color, LedEffect::Flash)
+        } else if self.audio_error {
+            (self.settings.error_color, self.settings.error_effect)
+        } else if self.is_deafened {
+            (self.settings.deafened_color, self.settings.deafened_effect)
+        } else if self.is_muted {
+            (self.settings.muted_color, self.settings.muted_effect)
+        } else {
+            (self.settings.unmuted_color, self.settings.unmuted_effect)
+        };
+        let effect;
+        if self.transition {
+            effect = state_effect;
+            self.transition = false;
+            // The state is only applied again after a failure, e.g. not on each keepalive.
+            // Otherwise a group would undo changes of another group to the same devices.
+            if self.audio_error || self.audio_muted != Some(self.is_muted) {
+                self.audio_muted = Some(self.is_muted);
+                actions.push(Action::SetMuted(self.is_muted));
+            }
+        } else {
+            effect = LedEffect::Solid;
+            actions.push(Action::ScheduleContinue(TRANSITION_DELAY));
+            self.transition = true;
+        }
+        let warning = self
+            .auto_mute_deadline()
+            .map_or(false, |deadline| deadline <= now + self.auto_mute_warning());
+        let effect = if warning {
+            self.settings.auto_mute_effect
+        } else {
+            effect
+        };
+        actions.push(Action::SetLight(LedState { color, effect }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::muteme::LongPressAction;
+
+    /// Builds a controller from settings, as the control thread does from the configuration.
+    struct ControllerBuilder {
+        settings: MuteMeSettings,
+        gesture_settings: GestureSettings,
+    }
+    impl ControllerBuilder {
+        fn new() -> Self {
+            Self {
+                settings: MuteMeSettings::default(),
+                gesture_settings: GestureSettings::default(),
+            }
+        }
+
+        fn settings(mut self, settings: MuteMeSettings) -> Self {
+            self.settings = settings;
+            self
+        }
+
+        fn gestures(mut self, gesture_settings: GestureSettings) -> Self {
+            self.gesture_settings = gesture_settings;
+            self
+        }
+
+        fn mode(mut self, mode: OperationMode) -> Self {
+            self.settings.operation_mode = mode;
+            self
+        }
+
+        fn long_press(mut self, action: GestureAction) -> Self {
+            self.gesture_settings.long_press = Some(action);
+            self
+        }
+
+        fn build(self) -> Controller {
+            Controller::new(self.settings, self.gesture_settings)
+        }
+    }
+
+    fn ms(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    /// Sends an event and completes the transition, as the control thread would.
+    fn send(c: &mut Controller, event: ControlEvent, now: Instant) -> Vec<Action> {
+        let mut actions = c.on_event(event, now);
+        if actions.contains(&Action::ScheduleContinue(TRANSITION_DELAY)) {
+            actions.extend(c.on_event(ControlEvent::Continue, now + TRANSITION_DELAY));
+        }
+        actions
+    }
+
+    fn tap(c: &mut Controller, touch: Instant, release: Instant) {
+        send(c, ControlEvent::Device(DeviceEvent::Touch), touch);
+        send(c, ControlEvent::Device(DeviceEvent::Release), release);
+    }
+
+    fn last_light(actions: &[Action]) -> Option<LedState> {
+        actions.iter().rev().find_map(|action| match action {
+            Action::SetLight(state) => Some(*state),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn change_shows_solid_light_then_flashes_and_applies() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        let actions = c.on_event(ControlEvent::SetMuted(true), start);
+        assert_eq!(
+            actions,
+            vec![
+                Action::ScheduleContinue(TRANSITION_DELAY),
+                Action::SetLight(LedState {
+                    color: Color::Red,
+                    effect: LedEffect::Solid
+                }),
+            ]
+        );
+        let actions = c.on_event(ControlEvent::Continue, ms(start, 100));
+        assert_eq!(
+            actions,
+            vec![
+                Action::SetMuted(true),
+                Action::SetLight(LedState {
+                    color: Color::Red,
+                    effect: LedEffect::Flash
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn keepalive_restarts_transition() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        c.on_event(ControlEvent::SetMuted(true), start);
+        let actions = c.on_event(ControlEvent::Keepalive, ms(start, 5000));
+        assert!(!actions.contains(&Action::SetMuted(true)));
+        assert!(actions.contains(&Action::ScheduleContinue(TRANSITION_DELAY)));
+        assert_eq!(last_light(&actions).unwrap().effect, LedEffect::Solid);
+    }
+
+    #[test]
+    fn keepalive_does_not_apply_state_again() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        let actions = send(&mut c, ControlEvent::SetMuted(true), start);
+        assert!(actions.contains(&Action::SetMuted(true)));
+        let actions = send(&mut c, ControlEvent::Keepalive, ms(start, 5000));
+        assert!(!actions.contains(&Action::SetMuted(true)));
+        assert_eq!(last_light(&actions).unwrap().color, Color::Red);
+    }
+
+    #[test]
+    fn reported_state_is_not_applied() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        let actions = send(&mut c, ControlEvent::MuteStatusChanged(true), start);
+        assert!(!actions.contains(&Action::SetMuted(true)));
+        assert_eq!(last_light(&actions).unwrap().color, Color::Red);
+        let actions = send(&mut c, ControlEvent::Toggle, ms(start, 1000));
+        assert!(actions.contains(&Action::SetMuted(false)));
+    }
+
+    #[test]
+    fn light_shows_effect_of_state() {
+        let start = Instant::now();
+        let mut c = Controller::new(
+            MuteMeSettings {
+                muted_effect: LedEffect::Solid,
+                unmuted_effect: LedEffect::Dim,
+                deafened_effect: LedEffect::SlowPulse,
+                ..MuteMeSettings::default()
+            },
+            GestureSettings::default(),
+        );
+        let actions = send(&mut c, ControlEvent::SetMuted(false), start);
+        assert_eq!(last_light(&actions).unwrap().effect, LedEffect::Dim);
+        let actions = send(&mut c, ControlEvent::SetMuted(true), ms(start, 1000));
+        assert_eq!(last_light(&actions).unwrap().effect, LedEffect::Solid);
+        let actions = send(&mut c, ControlEvent::SetDeafened(true), ms(start, 2000));
+        assert_eq!(
+            last_light(&actions),
+            Some(LedState {
+                color: Color::Blue,
+                effect: LedEffect::SlowPulse
+            })
+        );
+    }
+
+    #[test]
+    fn audio_error_shows_error_light() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        send(&mut c, ControlEvent::SetMuted(true), start);
+        let actions = send(
+            &mut c,
+            ControlEvent::AudioErrorChanged(true),
+            ms(start, 1000),
+        );
+        assert_eq!(
+            last_light(&actions),
+            Some(LedState {
+                color: Color::Yellow,
+                effect: LedEffect::SlowPulse
+            })
+        );
+        // The state is still applied, so that the audio thread can retry.
+        assert!(actions.contains(&Action::SetMuted(true)));
+        let actions = send(&mut c, ControlEvent::Keepalive, ms(start, 6000));
+        assert!(actions.contains(&Action::SetMuted(true)));
+        assert_eq!(last_light(&actions).unwrap().color, Color::Yellow);
+    }
+
+    #[test]
+    fn audio_error_clears_when_recovered() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        send(&mut c, ControlEvent::SetMuted(true), start);
+        send(
+            &mut c,
+            ControlEvent::AudioErrorChanged(true),
+            ms(start, 1000),
+        );
+        let actions = send(
+            &mut c,
+            ControlEvent::AudioErrorChanged(false),
+            ms(start, 2000),
+        );
+        assert_eq!(
+            last_light(&actions),
+            Some(LedState {
+                color: Color::Red,
+                effect: LedEffect::Flash
+            })
+        );
+    }
+
+    #[test]
+    fn toggle_changes_state_on_release() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        send(&mut c, ControlEvent::Device(DeviceEvent::Touch), start);
+        assert!(!c.status().muted);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 50),
+        );
+        assert!(c.status().muted);
+        tap(&mut c, ms(start, 1000), ms(start, 1050));
+        assert!(!c.status().muted);
+    }
+
+    #[test]
+    fn toggle_double_tap_without_deafen_toggles_twice() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        tap(&mut c, start, ms(start, 50));
+        tap(&mut c, ms(start, 100), ms(start, 150));
+        assert!(!c.status().muted);
+        assert!(!c.status().deafened);
+    }
+
+    #[test]
+    fn push_to_talk_unmutes_while_touched() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .mode(OperationMode::PushToTalk)
+            .build();
+        send(&mut c, ControlEvent::SetMuted(true), start);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Touch),
+            ms(start, 1000),
+        );
+        assert!(!c.status().muted);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 3000),
+        );
+        assert!(c.status().muted);
+    }
+
+    #[test]
+    fn hybrid_single_tap_talks_while_touched() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Hybrid).build();
+        send(&mut c, ControlEvent::SetMuted(true), start);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Touch),
+            ms(start, 1000),
+        );
+        assert!(!c.status().muted);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 1500),
+        );
+        assert!(c.status().muted);
+    }
+
+    #[test]
+    fn hybrid_double_tap_stays_unmuted() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Hybrid).build();
+        send(&mut c, ControlEvent::SetMuted(true), start);
+        tap(&mut c, ms(start, 1000), ms(start, 1100));
+        assert!(c.status().muted);
+        tap(&mut c, ms(start, 1200), ms(start, 1300));
+        assert!(!c.status().muted);
+    }
+
+    #[test]
+    fn hybrid_double_tap_ends_with_next_tap() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Hybrid).build();
+        send(&mut c, ControlEvent::SetMuted(true), start);
+        tap(&mut c, ms(start, 1000), ms(start, 1100));
+        tap(&mut c, ms(start, 1200), ms(start, 1300));
+        assert!(!c.status().muted);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Touch),
+            ms(start, 5000),
+        );
+        assert!(!c.status().muted);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 5100),
+        );
+        assert!(c.status().muted);
+    }
+
+    #[test]
+    fn hybrid_second_touch_too_late_is_single_tap() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Hybrid).build();
+        send(&mut c, ControlEvent::SetMuted(true), start);
+        tap(&mut c, ms(start, 1000), ms(start, 1100));
+        // Exactly at double_tap_duration_1 is too late.
+        tap(&mut c, ms(start, 1300), ms(start, 1350));
+        assert!(c.status().muted);
+    }
+
+    #[test]
+    fn hybrid_second_touch_held_too_long_mutes() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Hybrid).build();
+        send(&mut c, ControlEvent::SetMuted(true), start);
+        tap(&mut c, ms(start, 1000), ms(start, 1100));
+        // Released exactly at double_tap_duration_2 after the second touch.
+        tap(&mut c, ms(start, 1200), ms(start, 1450));
+        assert!(c.status().muted);
+    }
+
+    #[test]
+    fn hybrid_second_touch_released_just_in_time_stays_unmuted() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Hybrid).build();
+        send(&mut c, ControlEvent::SetMuted(true), start);
+        tap(&mut c, ms(start, 1000), ms(start, 1100));
+        tap(&mut c, ms(start, 1299), ms(start, 1548));
+        assert!(!c.status().muted);
+    }
+
+    #[test]
+    fn deafen_mutes_and_restores_previous_state() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        let actions = send(&mut c, ControlEvent::SetDeafened(true), start);
+        assert!(actions.contains(&Action::SetDeafened(true)));
+        assert!(actions.contains(&Action::SetMuted(true)));
+        assert_eq!(last_light(&actions).unwrap().color, Color::Blue);
+        assert!(c.status().muted && c.status().deafened);
+
+        let actions = send(&mut c, ControlEvent::SetDeafened(true), ms(start, 100));
+        assert!(!actions.contains(&Action::SetDeafened(true)));
+
+        let actions = send(&mut c, ControlEvent::ToggleDeafen, ms(start, 200));
+        assert!(actions.contains(&Action::SetDeafened(false)));
+        assert!(!c.status().muted && !c.status().deafened);
+        assert_eq!(last_light(&actions).unwrap().color, Color::Green);
+    }
+
+    #[test]
+    fn unmute_while_deafened_ends_deafen() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        send(&mut c, ControlEvent::SetDeafened(true), start);
+        let actions = send(&mut c, ControlEvent::SetMuted(false), ms(start, 1000));
+        assert!(actions.contains(&Action::SetDeafened(false)));
+        assert!(actions.contains(&Action::SetMuted(false)));
+        assert!(!c.status().muted && !c.status().deafened);
+        assert_eq!(last_light(&actions).unwrap().color, Color::Green);
+
+        send(&mut c, ControlEvent::SetDeafened(true), ms(start, 2000));
+        let actions = send(&mut c, ControlEvent::Toggle, ms(start, 3000));
+        assert!(actions.contains(&Action::SetDeafened(false)));
+        assert!(!c.status().deafened);
+
+        send(&mut c, ControlEvent::SetDeafened(true), ms(start, 4000));
+        let actions = send(
+            &mut c,
+            ControlEvent::MuteStatusChanged(false),
+            ms(start, 5000),
+        );
+        assert!(actions.contains(&Action::SetDeafened(false)));
+        assert_eq!(last_light(&actions).unwrap().color, Color::Green);
+    }
+
+    #[test]
+    fn toggle_double_tap_deafens_when_enabled() {
+        let start = Instant::now();
+        let mut c = Controller::new(
+            MuteMeSettings {
+                deafen_on_double_tap: true,
+                ..MuteMeSettings::default()
+            },
+            GestureSettings::default(),
+        );
+        tap(&mut c, start, ms(start, 50));
+        tap(&mut c, ms(start, 100), ms(start, 150));
+        assert!(c.status().deafened);
+        assert!(c.status().muted);
+
+        // Any tap ends deafen, with the state from before the double tap.
+        tap(&mut c, ms(start, 1000), ms(start, 1050));
+        assert!(c.status().deafened);
+        send(&mut c, ControlEvent::Continue, ms(start, 1300));
+        assert!(!c.status().deafened);
+        assert!(!c.status().muted);
+
+        // Single taps wait for a possible second tap.
+        tap(&mut c, ms(start, 1400), ms(start, 1450));
+        assert!(!c.status().muted);
+        send(&mut c, ControlEvent::Continue, ms(start, 1700));
+        assert!(!c.status().deafened);
+        assert!(c.status().muted);
+    }
+
+    #[test]
+    fn external_change_updates_state() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        let actions = send(&mut c, ControlEvent::MuteStatusChanged(true), start);
+        assert!(c.status().muted);
+        assert_eq!(last_light(&actions).unwrap().color, Color::Red);
+    }
+
+    #[test]
+    fn set_mode_mutes() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        send(
+            &mut c,
+            ControlEvent::SetMode(OperationMode::PushToTalk),
+            start,
+        );
+        assert!(c.status().muted);
+        assert_eq!(c.status().operation_mode, OperationMode::PushToTalk);
+    }
+
+    #[test]
+    fn set_color_changes_light() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        let actions = send(&mut c, ControlEvent::SetColor(false, Color::Cyan), start);
+        assert_eq!(last_light(&actions).unwrap().color, Color::Cyan);
+    }
+
+    #[test]
+    fn status_reports_device_connection() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        assert!(!c.status().device_connected);
+        c.on_event(ControlEvent::SetDeviceConnected(true), start);
+        assert!(c.status().device_connected);
+    }
+
+    #[test]
+    fn touch_schedules_long_press_check() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .mode(OperationMode::Toggle)
+            .long_press(GestureAction::Deafen)
+            .build();
+        let actions = c.on_event(ControlEvent::Device(DeviceEvent::Touch), start);
+        assert!(actions.contains(&Action::ScheduleContinue(Duration::from_millis(1000))));
+    }
+
+    #[test]
+    fn short_press_is_not_long_press() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .mode(OperationMode::Toggle)
+            .long_press(GestureAction::SwitchProfile)
+            .build();
+        send(&mut c, ControlEvent::Device(DeviceEvent::Touch), start);
+        c.on_event(ControlEvent::Continue, ms(start, 999));
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 999),
+        );
+        assert!(c.status().muted);
+        assert_eq!(c.status().operation_mode, OperationMode::Toggle);
+        // The scheduled check arrives after the release.
+        send(&mut c, ControlEvent::Continue, ms(start, 1000));
+        assert_eq!(c.status().operation_mode, OperationMode::Toggle);
+    }
+
+    #[test]
+    fn long_press_switches_mode_and_consumes_release() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .mode(OperationMode::Toggle)
+            .long_press(GestureAction::SwitchProfile)
+            .build();
+        send(&mut c, ControlEvent::Device(DeviceEvent::Touch), start);
+        let actions = send(&mut c, ControlEvent::Continue, ms(start, 1000));
+        assert_eq!(last_light(&actions).unwrap().color, Color::White);
+        assert_eq!(c.status().operation_mode, OperationMode::PushToTalk);
+        assert!(c.status().muted);
+
+        let actions = send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 1500),
+        );
+        assert_eq!(last_light(&actions).unwrap().color, Color::Red);
+        assert!(c.status().muted);
+
+        // Holding in push to talk mode talks until the threshold, then switches back.
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Touch),
+            ms(start, 3000),
+        );
+        assert!(!c.status().muted);
+        send(&mut c, ControlEvent::Continue, ms(start, 4000));
+        assert_eq!(c.status().operation_mode, OperationMode::Toggle);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 4100),
+        );
+        assert!(c.status().muted);
+    }
+
+    #[test]
+    fn long_press_detected_on_late_release() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .mode(OperationMode::Toggle)
+            .long_press(GestureAction::Deafen)
+            .build();
+        send(&mut c, ControlEvent::Device(DeviceEvent::Touch), start);
+        let actions = send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 1200),
+        );
+        assert!(actions.contains(&Action::SetDeafened(true)));
+        assert!(c.status().deafened);
+    }
+
+    #[test]
+    fn long_press_deafen_keeps_state_from_before_touch() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .mode(OperationMode::Hybrid)
+            .long_press(GestureAction::Deafen)
+            .build();
+        send(&mut c, ControlEvent::SetMuted(true), start);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Touch),
+            ms(start, 1000),
+        );
+        assert!(!c.status().muted);
+        send(&mut c, ControlEvent::Continue, ms(start, 2000));
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 2100),
+        );
+        assert!(c.status().deafened);
+        send(&mut c, ControlEvent::ToggleDeafen, ms(start, 3000));
+        assert!(c.status().muted);
+    }
+
+    #[test]
+    fn legacy_long_press_action_is_used_without_gesture() {
+        let start = Instant::now();
+        let settings = MuteMeSettings {
+            long_press_action: LongPressAction::Command,
+            long_press_command: String::from("notify-send hello"),
+            ..MuteMeSettings::default()
+        };
+        let mut c = Controller::new(settings.clone(), GestureSettings::default());
+        send(&mut c, ControlEvent::Device(DeviceEvent::Touch), start);
+        let actions = send(&mut c, ControlEvent::Continue, ms(start, 1000));
+        assert!(actions.contains(&Action::RunCommand(String::from("notify-send hello"))));
+
+        // The gesture settings take precedence.
+        let mut c = Controller::new(
+            settings,
+            GestureSettings {
+                long_press: Some(GestureAction::None),
+                ..GestureSettings::default()
+            },
+        );
+        send(&mut c, ControlEvent::Device(DeviceEvent::Touch), start);
+        let actions = send(&mut c, ControlEvent::Continue, ms(start, 1000));
+        assert!(!actions.iter().any(|a| matches!(a, Action::RunCommand(_))));
+    }
+
+    #[test]
+    fn long_press_runs_command() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .mode(OperationMode::Toggle)
+            .long_press(GestureAction::Command(String::from("notify-send hello")))
+            .build();
+        send(&mut c, ControlEvent::Device(DeviceEvent::Touch), start);
+        let actions = send(&mut c, ControlEvent::Continue, ms(start, 1000));
+        assert!(actions.contains(&Action::RunCommand(String::from("notify-send hello"))));
+        // Only once per touch.
+        let actions = send(&mut c, ControlEvent::Continue, ms(start, 2000));
+        assert!(!actions.iter().any(|a| matches!(a, Action::RunCommand(_))));
+    }
+
+    #[test]
+    fn triple_tap_waits_for_all_taps() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .mode(OperationMode::Toggle)
+            .gestures(GestureSettings {
+                triple_tap: Some(GestureAction::Command(String::from("mpc toggle"))),
+                ..GestureSettings::default()
+            })
+            .build();
+        tap(&mut c, start, ms(start, 50));
+        tap(&mut c, ms(start, 200), ms(start, 250));
+        let actions = send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Touch),
+            ms(start, 400),
+        );
+        assert!(!c.status().muted);
+        let actions = [
+            actions,
+            send(
+                &mut c,
+                ControlEvent::Device(DeviceEvent::Release),
+                ms(start, 450),
+            ),
+        ]
+        .concat();
+        assert!(actions.contains(&Action::RunCommand(String::from("mpc toggle"))));
+        assert!(!c.status().muted);
+
+        // Two taps have no action, but still have to wait for the third.
+        tap(&mut c, ms(start, 1000), ms(start, 1050));
+        tap(&mut c, ms(start, 1200), ms(start, 1250));
+        send(&mut c, ControlEvent::Continue, ms(start, 1500));
+        assert!(!c.status().muted);
+        tap(&mut c, ms(start, 2000), ms(start, 2050));
+        send(&mut c, ControlEvent::Continue, ms(start, 2300));
+        assert!(c.status().muted);
+    }
+
+    #[test]
+    fn waiting_tap_runs_on_next_touch_after_window() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .mode(OperationMode::Toggle)
+            .gestures(GestureSettings {
+                double_tap: Some(GestureAction::Mute),
+                ..GestureSettings::default()
+            })
+            .build();
+        let actions = send(&mut c, ControlEvent::Device(DeviceEvent::Touch), start);
+        assert!(!actions
+            .iter()
+            .any(|a| *a == Action::ScheduleContinue(Duration::from_millis(1000))));
+        let actions = send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 50),
+        );
+        assert!(actions.contains(&Action::ScheduleContinue(Duration::from_millis(250))));
+        assert!(!c.status().muted);
+        // The scheduled check was missed.
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Touch),
+            ms(start, 300),
+        );
+        assert!(c.status().muted);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 350),
+        );
+        send(&mut c, ControlEvent::Continue, ms(start, 600));
+        assert!(!c.status().muted);
+    }
+
+    #[test]
+    fn held_second_touch_starts_new_sequence() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .mode(OperationMode::Toggle)
+            .gestures(GestureSettings {
+                double_tap: Some(GestureAction::Deafen),
+                ..GestureSettings::default()
+            })
+            .build();
+        tap(&mut c, start, ms(start, 50));
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Touch),
+            ms(start, 200),
+        );
+        c.on_event(ControlEvent::Device(DeviceEvent::Release), ms(start, 450));
+        // The first tap has run, the second one waits.
+        assert!(c.status().muted);
+        assert!(!c.status().deafened);
+        send(&mut c, ControlEvent::Continue, ms(start, 500));
+        assert!(!c.status().muted);
+        assert!(!c.status().deafened);
+    }
+
+    #[test]
+    fn momentary_mute_on_hold() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .mode(OperationMode::Toggle)
+            .gestures(GestureSettings {
+                tap: Some(GestureAction::None),
+                hold: Some(GestureAction::MomentaryMute),
+                ..GestureSettings::default()
+            })
+            .build();
+        send(&mut c, ControlEvent::Device(DeviceEvent::Touch), start);
+        assert!(c.status().muted);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 2000),
+        );
+        assert!(!c.status().muted);
+    }
+
+    #[test]
+    fn double_tap_switches_profile() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .mode(OperationMode::PushToTalk)
+            .gestures(GestureSettings {
+                double_tap: Some(GestureAction::SwitchProfile),
+                profiles: vec![OperationMode::PushToTalk, OperationMode::Hybrid],
+                ..GestureSettings::default()
+            })
+            .build();
+        tap(&mut c, start, ms(start, 50));
+        tap(&mut c, ms(start, 100), ms(start, 150));
+        assert_eq!(c.status().operation_mode, OperationMode::Hybrid);
+        assert!(c.status().muted);
+        // The configured double tap replaces the one of the preset.
+        tap(&mut c, ms(start, 1000), ms(start, 1050));
+        tap(&mut c, ms(start, 1100), ms(start, 1150));
+        assert_eq!(c.status().operation_mode, OperationMode::PushToTalk);
+        assert!(c.status().muted);
+    }
+
+    #[test]
+    fn presets_match_operation_modes() {
+        let toggle = Gestures::preset(&OperationMode::Toggle, false);
+        assert_eq!(toggle.tap, GestureAction::Toggle);
+        assert_eq!(toggle.max_taps(), 1);
+        let toggle = Gestures::preset(&OperationMode::Toggle, true);
+        assert_eq!(toggle.double_tap, GestureAction::Deafen);
+        assert_eq!(toggle.max_taps(), 2);
+        let push_to_talk = Gestures::preset(&OperationMode::PushToTalk, true);
+        assert_eq!(push_to_talk.hold, GestureAction::MomentaryUnmute);
+        assert_eq!(push_to_talk.max_taps(), 0);
+        let hybrid = Gestures::preset(&OperationMode::Hybrid, false);
+        assert_eq!(hybrid.double_tap, GestureAction::Unmute);
+        assert_eq!(hybrid.max_taps(), 2);
+    }
+
+    #[test]
+    fn push_to_mute_mutes_while_touched() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .settings(MuteMeSettings {
+                operation_mode: OperationMode::PushToMute,
+                mute_hold_delay: 0,
+                ..MuteMeSettings::default()
+            })
+            .build();
+        assert!(!c.status().muted);
+        send(&mut c, ControlEvent::Device(DeviceEvent::Touch), start);
+        assert!(c.status().muted);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 3000),
+        );
+        assert!(!c.status().muted);
+    }
+
+    #[test]
+    fn push_to_mute_ignores_touches_shorter_than_delay() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .settings(MuteMeSettings {
+                operation_mode: OperationMode::PushToMute,
+                mute_hold_delay: 200,
+                ..MuteMeSettings::default()
+            })
+            .build();
+        let actions = send(&mut c, ControlEvent::Device(DeviceEvent::Touch), start);
+        assert!(actions.contains(&Action::ScheduleContinue(Duration::from_millis(200))));
+        assert!(!c.status().muted);
+        let actions = [
+            c.on_event(ControlEvent::Continue, ms(start, 199)),
+            send(
+                &mut c,
+                ControlEvent::Device(DeviceEvent::Release),
+                ms(start, 199),
+            ),
+            send(&mut c, ControlEvent::Continue, ms(start, 200)),
+        ]
+        .concat();
+        assert!(!actions.contains(&Action::SetMuted(true)));
+        assert!(!c.status().muted);
+    }
+
+    #[test]
+    fn push_to_mute_mutes_after_delay() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .settings(MuteMeSettings {
+                operation_mode: OperationMode::PushToMute,
+                mute_hold_delay: 200,
+                ..MuteMeSettings::default()
+            })
+            .build();
+        send(&mut c, ControlEvent::Device(DeviceEvent::Touch), start);
+        send(&mut c, ControlEvent::Continue, ms(start, 200));
+        assert!(c.status().muted);
+        send(
+            &mut c,
+            ControlEvent::Device(DeviceEvent::Release),
+            ms(start, 2000),
+        );
+        assert!(!c.status().muted);
+    }
+
+    fn secs(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    fn scheduled(actions: &[Action]) -> Vec<Duration> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::ScheduleContinue(delay) if *delay != TRANSITION_DELAY => Some(*delay),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unmuting_without_auto_mute_schedules_nothing() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new().mode(OperationMode::Toggle).build();
+        send(&mut c, ControlEvent::SetMuted(true), start);
+        let actions = send(&mut c, ControlEvent::SetMuted(false), secs(start, 1));
+        assert!(scheduled(&actions).is_empty());
+    }
+
+    #[test]
+    fn auto_mute_warns_then_mutes() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .settings(MuteMeSettings {
+                auto_mute_after: Some(1),
+                ..MuteMeSettings::default()
+            })
+            .build();
+        let actions = send(&mut c, ControlEvent::SetMuted(true), start);
+        assert!(scheduled(&actions).is_empty());
+        let actions = send(&mut c, ControlEvent::SetMuted(false), secs(start, 1));
+        assert_eq!(
+            scheduled(&actions),
+            vec![Duration::from_secs(50), Duration::from_secs(60)]
+        );
+
+        let actions = send(&mut c, ControlEvent::Continue, secs(start, 51));
+        assert!(!c.status().muted);
+        assert_eq!(last_light(&actions).unwrap().effect, LedEffect::FastPulse);
+
+        let actions = send(&mut c, ControlEvent::Continue, secs(start, 61));
+        assert!(c.status().muted);
+        assert!(actions.contains(&Action::SetMuted(true)));
+        assert_eq!(last_light(&actions).unwrap().effect, LedEffect::Flash);
+        assert!(scheduled(&actions).is_empty());
+    }
+
+    #[test]
+    fn auto_mute_restarts_when_unmuted_again() {
+        let start = Instant::now();
+        let mut c = ControllerBuilder::new()
+            .settings(MuteMeSettings {
+                auto_mute_after: Some(1),
+                ..MuteMeSettings::default()
+            })
+            .build();
+        send(&mut c, ControlEvent::SetMuted(false), start);
+        tap(&mut c, secs(start, 30), secs(start, 30));
+        tap(&mut c, secs(start, 40), secs(start, 40));
+        assert!(!c.status().muted);
+        send(&mut c, ControlEvent::Continue, secs(start, 61));
+        assert!(!c.status().muted);
+        send(&mut c, ControlEvent::Continue, secs(start, 100));
+        assert!(c.status().muted);
+    }
+}
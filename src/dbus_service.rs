@@ -0,0 +1,392 @@
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use dbus::{
+    arg::{PropMap, RefArg, Variant},
+    blocking::{stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged, Connection},
+    channel::{MatchingReceiver, Sender as _},
+    message::{MatchRule, SignalArgs},
+    strings::{BusName, Interface, Member},
+    Message, Path,
+};
+use dbus_crossroads::{Crossroads, IfaceBuilder, MethodErr};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::muteme::{ControlMessage, ControlStatus, OperationMode};
+
+pub const BUS_NAME: &str = "org.mutebtn.Daemon";
+const OBJECT_PATH: &str = "/org/mutebtn/Daemon";
+/// Maximum time the service waits for D-Bus messages. Status changes wake it up right away.
+const PROCESS_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct DaemonState {
+    ctrl_senders: Vec<Sender<ControlMessage>>,
+    status: Arc<Mutex<Option<ControlStatus>>>,
+}
+impl DaemonState {
+    fn send_all<F: Fn() -> ControlMessage>(&self, message: F) {
+        for ctrl_sender in &self.ctrl_senders {
+            ctrl_sender.send(message()).unwrap_or(());
+        }
+    }
+
+    fn status(&self) -> Result<ControlStatus, MethodErr> {
+        match &*self.status.lock().unwrap() {
+            Some(status) => Ok(status.clone()),
+            None => Err(MethodErr::failed("No status available")),
+        }
+    }
+}
+
+/// Registers the service on the session bus. Method calls are forwarded to all control
+/// threads; properties and signals reflect their combined status.
+pub fn spawn_service(ctrl_senders: Vec<Sender<ControlMessage>>) {
+    thread::spawn(move || {
+        if let Err(err) = run_service(ctrl_senders) {
+            println!("D-Bus service failed: {}", err);
+        }
+    });
+}
+
+fn run_service(ctrl_senders: Vec<Sender<ControlMessage>>) -> Result<(), dbus::Error> {
+    let conn = Connection::new_session()?;
+    conn.request_name(BUS_NAME, false, true, false)?;
+    println!("Registered {} on the session bus", BUS_NAME);
+
+    let (status_sender, forward_receiver) = unbounded();
+    let (forward_sender, status_receiver) = unbounded();
+    spawn_status_forwarder(
+        forward_receiver,
+        forward_sender,
+        conn.unique_name().to_string(),
+    )?;
+    for ctrl_sender in &ctrl_senders {
+        ctrl_sender
+            .send(ControlMessage::Subscribe(status_sender.clone()))
+            .unwrap_or(());
+    }
+    let status = Arc::new(Mutex::new(None));
+
+    let mut cr = Crossroads::new();
+    let iface = cr.register(BUS_NAME, |b: &mut IfaceBuilder<DaemonState>| {
+        b.signal::<(bool,), _>("MuteChanged", ("muted",));
+        b.method("Mute", (), (), |_, state: &mut DaemonState, _: ()| {
+            state.send_all(|| ControlMessage::SetMuted(true));
+            Ok(())
+        });
+        b.method("Unmute", (), (), |_, state: &mut DaemonState, _: ()| {
+            state.send_all(|| ControlMessage::SetMuted(false));
+            Ok(())
+        });
+        b.method("Toggle", (), (), |_, state: &mut DaemonState, _: ()| {
+            state.send_all(|| ControlMessage::Toggle);
+            Ok(())
+        });
+        b.method("Deafen", (), (), |_, state: &mut DaemonState, _: ()| {
+            state.send_all(|| ControlMessage::SetDeafened(true));
+            Ok(())
+        });
+        b.method("Undeafen", (), (), |_, state: &mut DaemonState, _: ()| {
+            state.send_all(|| ControlMessage::SetDeafened(false));
+            Ok(())
+        });
+        b.method(
+            "ToggleDeafen",
+            (),
+            (),
+            |_, state: &mut DaemonState, _: ()| {
+                state.send_all(|| ControlMessage::ToggleDeafen);
+                Ok(())
+            },
+        );
+        b.method(
+            "SetMode",
+            ("mode",),
+            (),
+            |_, state: &mut DaemonState, (mode,): (String,)| {
+                let new_mode: OperationMode = mode
+                    .parse()
+                    .map_err(|err: String| MethodErr::invalid_arg(&err))?;
+                state.send_all(|| ControlMessage::SetMode(new_mode.clone()));
+                Ok(())
+            },
+        );
+        b.method(
+            "SetColor",
+            ("muted", "color"),
+            (),
+            |_, state: &mut DaemonState, (mute_state, color): (bool, String)| {
+                let new_color = color
+                    .parse()
+                    .map_err(|err: String| MethodErr::invalid_arg(&err))?;
+                state.send_all(|| ControlMessage::SetColor(mute_state, new_color));
+                Ok(())
+            },
+        );
+        b.property("Muted")
+            .get(|_, state: &mut DaemonState| Ok(state.status()?.muted));
+        b.property("Deafened")
+            .get(|_, state: &mut DaemonState| Ok(state.status()?.deafened));
+        b.property("OperationMode")
+            .get(|_, state: &mut DaemonState| Ok(state.status()?.operation_mode.to_string()));
+        b.property("DeviceConnected")
+            .get(|_, state: &mut DaemonState| Ok(state.status()?.device_connected));
+    });
+    cr.insert(
+        OBJECT_PATH,
+        &[iface],
+        DaemonState {
+            ctrl_senders: ctrl_senders.clone(),
+            status: Arc::clone(&status),
+        },
+    );
+    conn.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            cr.handle_message(msg, conn).unwrap_or(());
+            true
+        }),
+    );
+
+    let path = Path::from(OBJECT_PATH);
+    let mut statuses: Vec<Option<ControlStatus>> = vec![None; ctrl_senders.len()];
+    let mut current: Option<ControlStatus> = None;
+    loop {
+        conn.process(PROCESS_TIMEOUT)?;
+        while let Ok((group, group_status)) = status_receiver.try_recv() {
+            statuses[group] = Some(group_status);
+        }
+        let combined = ControlStatus::combine(statuses.iter().flatten());
+        if combined == current {
+            continue;
+        }
+        if let Some(new_status) = &combined {
+            for msg in changed_messages(&path, current.as_ref(), new_status) {
+                conn.send(msg).unwrap_or(0);
+            }
+        }
+        *status.lock().unwrap() = combined.clone();
+        current = combined;
+    }
+}
+
+/// Forwards status changes to the service thread, and wakes it up with a signal to its
+/// connection. Messages cannot be sent on the connection from another thread while it is
+/// waiting, so the signal is sent through a second connection.
+fn spawn_status_forwarder(
+    status_receiver: Receiver<(usize, ControlStatus)>,
+    status_sender: Sender<(usize, ControlStatus)>,
+    destination: String,
+) -> Result<(), dbus::Error> {
+    let conn = Connection::new_session()?;
+    thread::spawn(move || {
+        for status in status_receiver.iter() {
+            if status_sender.send(status).is_err() {
+                break;
+            }
+            let mut wakeup = Message::signal(
+                &Path::from(OBJECT_PATH),
+                &Interface::from(BUS_NAME),
+                &Member::from("Wakeup"),
+            );
+            wakeup.set_destination(Some(BusName::from(destination.as_str())));
+            conn.send(wakeup).unwrap_or(0);
+        }
+    });
+    Ok(())
+}
+
+/// Returns the `MuteChanged` signal and the property change notifications for a status change.
+fn changed_messages(
+    path: &Path,
+    old_status: Option<&ControlStatus>,
+    new_status: &ControlStatus,
+) -> Vec<Message> {
+    let mut messages = Vec::new();
+    let mut changed_properties = PropMap::new();
+    let mut add_property = |name: &str, value: Box<dyn RefArg>| {
+        changed_properties.insert(String::from(name), Variant(value));
+    };
+    if old_status.map(|s| s.muted) != Some(new_status.muted) {
+        add_property("Muted", Box::new(new_status.muted));
+        messages.push(
+            Message::signal(
+                path,
+                &Interface::from(BUS_NAME),
+                &Member::from("MuteChanged"),
+            )
+            .append1(new_status.muted),
+        );
+    }
+    if old_status.map(|s| s.deafened) != Some(new_status.deafened) {
+        add_property("Deafened", Box::new(new_status.deafened));
+    }
+    if old_status.map(|s| &s.operation_mode) != Some(&new_status.operation_mode) {
+        add_property(
+            "OperationMode",
+            Box::new(new_status.operation_mode.to_string()),
+        );
+    }
+    if old_status.map(|s| s.device_connected) != Some(new_status.device_connected) {
+        add_property("DeviceConnected", Box::new(new_status.device_connected));
+    }
+    let properties_changed = PropertiesPropertiesChanged {
+        interface_name: String::from(BUS_NAME),
+        changed_properties,
+        invalidated_properties: Vec::new(),
+    };
+    messages.push(properties_changed.to_emit_message(path));
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+    use std::{
+        env,
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        time::Instant,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn status(muted: bool, deafened: bool) -> ControlStatus {
+        ControlStatus {
+            muted,
+            deafened,
+            operation_mode: OperationMode::Toggle,
+            device_connected: true,
+        }
+    }
+
+    fn changed_properties(messages: &[Message]) -> Vec<String> {
+        let changed = messages
+            .iter()
+            .find_map(PropertiesPropertiesChanged::from_message)
+            .unwrap();
+        let mut names: Vec<String> = changed.changed_properties.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn first_status_announces_all_properties() {
+        let path = Path::from(OBJECT_PATH);
+        let messages = changed_messages(&path, None, &status(true, false));
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].member().unwrap().to_string(), "MuteChanged");
+        assert_eq!(messages[0].get1(), Some(true));
+        assert_eq!(
+            changed_properties(&messages),
+            vec!["Deafened", "DeviceConnected", "Muted", "OperationMode"]
+        );
+    }
+
+    #[test]
+    fn status_change_announces_changed_properties() {
+        let path = Path::from(OBJECT_PATH);
+        let old_status = status(true, false);
+        let messages = changed_messages(&path, Some(&old_status), &status(true, true));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(changed_properties(&messages), vec!["Deafened"]);
+
+        let messages = changed_messages(&path, Some(&old_status), &status(false, false));
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].get1(), Some(false));
+        assert_eq!(changed_properties(&messages), vec!["Muted"]);
+    }
+
+    /// Session bus of its own for the test, which is stopped when dropped.
+    struct TestBus(Child);
+    impl TestBus {
+        fn start() -> Option<Self> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .ok()?;
+            env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+            Some(Self(daemon))
+        }
+    }
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            self.0.kill().unwrap_or(());
+            self.0.wait().unwrap_or_default();
+        }
+    }
+
+    #[test]
+    fn methods_and_properties_on_private_bus() {
+        let _bus = match TestBus::start() {
+            Some(bus) => bus,
+            None => {
+                println!("dbus-daemon not available, skipping");
+                return;
+            },
+        };
+        let (ctrl_sender, ctrl_receiver) = unbounded();
+        spawn_service(vec![ctrl_sender]);
+        let status_sender = match ctrl_receiver.recv_timeout(TIMEOUT) {
+            Ok(ControlMessage::Subscribe(status_sender)) => status_sender,
+            _ => panic!("Service did not subscribe"),
+        };
+
+        let conn = Connection::new_session().unwrap();
+        conn.add_match_no_cb(&format!(
+            "type='signal',interface='{}',member='MuteChanged'",
+            BUS_NAME
+        ))
+        .unwrap();
+        let proxy = conn.with_proxy(BUS_NAME, OBJECT_PATH, TIMEOUT);
+        let start = Instant::now();
+        while proxy
+            .method_call::<(), _, _, _>(BUS_NAME, "Toggle", ())
+            .is_err()
+        {
+            assert!(start.elapsed() < TIMEOUT, "Service did not register");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(
+            ctrl_receiver.recv_timeout(TIMEOUT),
+            Ok(ControlMessage::Toggle)
+        ));
+        proxy
+            .method_call::<(), _, _, _>(BUS_NAME, "SetMode", ("pushtotalk",))
+            .unwrap();
+        assert!(matches!(
+            ctrl_receiver.recv_timeout(TIMEOUT),
+            Ok(ControlMessage::SetMode(OperationMode::PushToTalk))
+        ));
+        assert!(proxy
+            .method_call::<(), _, _, _>(BUS_NAME, "SetMode", ("unknown",))
+            .is_err());
+
+        // Status changes are announced without waiting for the process timeout.
+        let sent = Instant::now();
+        status_sender.send((0, status(true, false))).unwrap();
+        let signal = loop {
+            let message = conn.channel().blocking_pop_message(TIMEOUT).unwrap();
+            match message {
+                Some(message) if message.member().as_deref() == Some("MuteChanged") => {
+                    break message;
+                },
+                Some(_) => continue,
+                None => panic!("No MuteChanged signal"),
+            }
+        };
+        assert!(sent.elapsed() < Duration::from_secs(1));
+        assert_eq!(signal.get1(), Some(true));
+        let muted: bool = proxy.get(BUS_NAME, "Muted").unwrap();
+        assert!(muted);
+    }
+}
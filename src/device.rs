@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Color {
+    Red,
+    Green,
+    Blue,
+    Yellow,
+    Cyan,
+    Purple,
+    White,
+    NoColor,
+}
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "red" => Ok(Self::Red),
+            "green" => Ok(Self::Green),
+            "blue" => Ok(Self::Blue),
+            "yellow" => Ok(Self::Yellow),
+            "cyan" => Ok(Self::Cyan),
+            "purple" => Ok(Self::Purple),
+            "white" => Ok(Self::White),
+            "nocolor" => Ok(Self::NoColor),
+            _ => Err(format!("Invalid color: {}", s)),
+        }
+    }
+}
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Red => "red",
+            Self::Green => "green",
+            Self::Blue => "blue",
+            Self::Yellow => "yellow",
+            Self::Cyan => "cyan",
+            Self::Purple => "purple",
+            Self::White => "white",
+            Self::NoColor => "nocolor",
+        };
+        write!(f, "{}", name)
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LedEffect {
+    Solid,
+    Dim,
+    FastPulse,
+    SlowPulse,
+    Flash,
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LedState {
+    pub color: Color,
+    pub effect: LedEffect,
+}
+#[derive(Debug)]
+pub enum DeviceEvent {
+    Touch,
+    Release,
+}
+#[derive(Debug)]
+pub enum DeviceError {
+    Disconnected(String),
+    Backend(String),
+}
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disconnected(message) => write!(f, "Device disconnected: {}", message),
+            Self::Backend(message) => write!(f, "Device error: {}", message),
+        }
+    }
+}
+/// Identifies one of several connected devices of the same type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceId {
+    pub path: String,
+    pub serial: Option<String>,
+}
+/// Matches devices by serial number and / or path. Selectors without any criteria do not
+/// match any device.
+#[derive(Debug, Clone)]
+pub struct DeviceSelector {
+    pub serial: Option<String>,
+    pub path: Option<String>,
+}
+impl DeviceSelector {
+    pub fn matches(&self, id: &DeviceId) -> bool {
+        if self.serial.is_none() && self.path.is_none() {
+            return false;
+        }
+        let serial_matches = match &self.serial {
+            Some(serial) => id.serial.as_ref() == Some(serial),
+            None => true,
+        };
+        let path_matches = match &self.path {
+            Some(path) => &id.path == path,
+            None => true,
+        };
+        serial_matches && path_matches
+    }
+}
+pub enum ExecMessage {
+    SetLight(usize, LedState),
+    Disconnected(DeviceId),
+    Terminate,
+}
+
+/// Input device that shows the mute state on its light. Its button events are read through
+/// a separate `EventReader`, so that reads can block without delaying the light.
+///
+/// Read and write errors are returned as `DeviceError::Disconnected`, after which the
+/// device should be dropped and opened again once it is available.
+pub trait MuteDevice {
+    fn id(&self) -> &DeviceId;
+    fn set_light(&mut self, state: &LedState) -> Result<(), DeviceError>;
+}
+
+/// Reads the button events of a `MuteDevice`.
+pub trait EventReader {
+    /// Waits up to the timeout for the next event.
+    fn read_event(&mut self, timeout: Duration) -> Result<Option<DeviceEvent>, DeviceError>;
+}
+
+/// Finds and opens the devices of one type.
+pub trait DeviceScanner {
+    type Device: MuteDevice;
+    type Reader: EventReader + Send + 'static;
+
+    /// Lists the connected devices.
+    fn scan(&mut self) -> Result<Vec<DeviceId>, DeviceError>;
+    /// Opens a device listed by `scan`, with a handle for its light and one for reading its
+    /// events on another thread.
+    fn open(&self, id: DeviceId) -> Result<(Self::Device, Self::Reader), DeviceError>;
+}
@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+use crate::muteme::OperationMode;
+
+/// Action triggered by a gesture. Momentary actions are undone when the button is released
+/// after a hold, and act like `mute` / `unmute` for other gestures.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "ActionConfig", into = "ActionConfig")]
+pub enum GestureAction {
+    None,
+    Toggle,
+    Mute,
+    Unmute,
+    MomentaryUnmute,
+    MomentaryMute,
+    Deafen,
+    SwitchProfile,
+    Command(String),
+}
+
+/// Configuration format of actions: Either the lowercase name, or `{ command = "..." }`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum ActionConfig {
+    Name(String),
+    Command { command: String },
+}
+impl TryFrom<ActionConfig> for GestureAction {
+    type Error = String;
+
+    fn try_from(config: ActionConfig) -> Result<Self, Self::Error> {
+        let name = match config {
+            ActionConfig::Name(name) => name,
+            ActionConfig::Command { command } => return Ok(Self::Command(command)),
+        };
+        match name.as_str() {
+            "none" => Ok(Self::None),
+            "toggle" => Ok(Self::Toggle),
+            "mute" => Ok(Self::Mute),
+            "unmute" => Ok(Self::Unmute),
+            "momentaryunmute" => Ok(Self::MomentaryUnmute),
+            "momentarymute" => Ok(Self::MomentaryMute),
+            "deafen" => Ok(Self::Deafen),
+            "switchprofile" => Ok(Self::SwitchProfile),
+            _ => Err(format!("Invalid gesture action: {}", name)),
+        }
+    }
+}
+impl From<GestureAction> for ActionConfig {
+    fn from(action: GestureAction) -> Self {
+        let name = match action {
+            GestureAction::None => "none",
+            GestureAction::Toggle => "toggle",
+            GestureAction::Mute => "mute",
+            GestureAction::Unmute => "unmute",
+            GestureAction::MomentaryUnmute => "momentaryunmute",
+            GestureAction::MomentaryMute => "momentarymute",
+            GestureAction::Deafen => "deafen",
+            GestureAction::SwitchProfile => "switchprofile",
+            GestureAction::Command(command) => return Self::Command { command },
+        };
+        Self::Name(name.to_string())
+    }
+}
+
+/// Maps gestures to actions. Gestures that are not set are taken from the preset of the
+/// current operation mode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GestureSettings {
+    pub tap: Option<GestureAction>,
+    pub double_tap: Option<GestureAction>,
+    pub triple_tap: Option<GestureAction>,
+    pub long_press: Option<GestureAction>,
+    pub hold: Option<GestureAction>,
+    /// Operation modes that `switchprofile` cycles through.
+    pub profiles: Vec<OperationMode>,
+}
+impl Default for GestureSettings {
+    fn default() -> Self {
+        Self {
+            tap: None,
+            double_tap: None,
+            triple_tap: None,
+            long_press: None,
+            hold: None,
+            profiles: vec![OperationMode::Toggle, OperationMode::PushToTalk],
+        }
+    }
+}
+
+/// Actions in effect for each gesture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gestures {
+    pub tap: GestureAction,
+    pub double_tap: GestureAction,
+    pub triple_tap: GestureAction,
+    pub long_press: GestureAction,
+    pub hold: GestureAction,
+}
+impl Gestures {
+    pub fn preset(mode: &OperationMode, deafen_on_double_tap: bool) -> Self {
+        let mut gestures = Self {
+            tap: GestureAction::None,
+            double_tap: GestureAction::None,
+            triple_tap: GestureAction::None,
+            long_press: GestureAction::None,
+            hold: GestureAction::None,
+        };
+        match mode {
+            OperationMode::Toggle => {
+                gestures.tap = GestureAction::Toggle;
+                if deafen_on_double_tap {
+                    gestures.double_tap = GestureAction::Deafen;
+                }
+            },
+            OperationMode::PushToTalk => {
+                gestures.hold = GestureAction::MomentaryUnmute;
+            },
+            OperationMode::PushToMute => {
+                gestures.hold = GestureAction::MomentaryMute;
+            },
+            OperationMode::Hybrid => {
+                gestures.hold = GestureAction::MomentaryUnmute;
+                gestures.double_tap = GestureAction::Unmute;
+            },
+        }
+        gestures
+    }
+
+    /// Returns the preset of the operation mode, with the configured gestures replaced.
+    pub fn new(
+        settings: &GestureSettings,
+        mode: &OperationMode,
+        deafen_on_double_tap: bool,
+    ) -> Self {
+        let preset = Self::preset(mode, deafen_on_double_tap);
+        let choose = |setting: &Option<GestureAction>, default: GestureAction| match setting {
+            Some(action) => action.clone(),
+            None => default,
+        };
+        Self {
+            tap: choose(&settings.tap, preset.tap),
+            double_tap: choose(&settings.double_tap, preset.double_tap),
+            triple_tap: choose(&settings.triple_tap, preset.triple_tap),
+            long_press: choose(&settings.long_press, preset.long_press),
+            hold: choose(&settings.hold, preset.hold),
+        }
+    }
+
+    /// Returns the action for a number of consecutive taps.
+    pub fn taps(&self, count: u8) -> &GestureAction {
+        match count {
+            1 => &self.tap,
+            2 => &self.double_tap,
+            3 => &self.triple_tap,
+            _ => &GestureAction::None,
+        }
+    }
+
+    /// Returns the highest number of consecutive taps that has an action. Taps are only
+    /// delayed to wait for further taps if there is an action for more of them.
+    pub fn max_taps(&self) -> u8 {
+        (1..=3)
+            .rev()
+            .find(|count| *self.taps(*count) != GestureAction::None)
+            .unwrap_or(0)
+    }
+}
@@ -1,10 +1,22 @@
+mod alsa_mixer;
+mod controller;
+#[cfg(feature = "dbus-service")]
+mod dbus_service;
+mod device;
+mod gestures;
+mod matching;
 mod muteme;
 mod pulse;
+#[cfg(feature = "pipewire")]
+mod pw;
+mod socket;
+mod timers;
+mod volume_store;
 
-use clap::{clap_app, ArgMatches};
+use alsa_mixer::AlsaSettings;
+use clap::{clap_app, Arg, ArgMatches};
 use config::{Config, ConfigError, File};
-use crossbeam_channel::{unbounded, RecvError, RecvTimeoutError};
-use hidapi::{HidDevice, HidError};
+use crossbeam_channel::{unbounded, Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError};
 use pulse::PulseSettings;
 use serde::{Deserialize, Serialize};
 use signal_hook::{
@@ -12,25 +24,102 @@ use signal_hook::{
     iterator::Signals,
 };
 use std::{
-    path::Path,
-    thread,
+    fs,
+    path::{Path, PathBuf},
+    process,
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
-use crate::muteme::{
-    ControlMessage, DeviceEvent, ExecMessage, IntMessage, MuteMeSettings, OperationMode,
+#[cfg(feature = "alsa")]
+use crate::alsa_mixer::AlsaControl;
+use crate::controller::{Action, ControlEvent, Controller, KEEPALIVE_INTERVAL};
+use crate::device::{
+    DeviceId, DeviceScanner, DeviceSelector, EventReader, ExecMessage, LedState, MuteDevice,
 };
-use crate::pulse::{AudioMessage, Mute, PulseControl};
+use crate::gestures::GestureSettings;
+use crate::muteme::{ControlMessage, ControlStatus, MuteMeScanner, MuteMeSettings};
+use crate::pulse::{AudioError, AudioMessage, Mute, PulseControl};
+#[cfg(feature = "pipewire")]
+use crate::pw::PipeWireControl;
+use crate::timers::Timers;
 
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum time a reader thread blocks before checking whether the device has been dropped.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+const COLORS: [&str; 8] = [
+    "red", "green", "blue", "yellow", "cyan", "purple", "white", "nocolor",
+];
+const MODES: [&str; 4] = ["toggle", "pushtotalk", "pushtomute", "hybrid"];
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AudioBackend {
+    Pulse,
+    PipeWire,
+    Alsa,
+}
+impl AudioBackend {
+    /// Returns an error if support for the backend has been left out at build time.
+    fn check_enabled(&self) -> Result<(), String> {
+        match self {
+            #[cfg(not(feature = "pipewire"))]
+            Self::PipeWire => Err(String::from(
+                "PipeWire support is not enabled, rebuild with --features pipewire",
+            )),
+            #[cfg(not(feature = "alsa"))]
+            Self::Alsa => Err(String::from(
+                "ALSA support is not enabled, rebuild with --features alsa",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(default)]
 struct MainSettings {
     mute_on_startup: Option<bool>,
+    socket_path: Option<String>,
+    dbus_service: bool,
+    audio_backend: AudioBackend,
 }
 impl Default for MainSettings {
     fn default() -> Self {
         Self {
             mute_on_startup: None,
+            socket_path: None,
+            dbus_service: true,
+            audio_backend: AudioBackend::Pulse,
+        }
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct ButtonSettings {
+    serial: Option<String>,
+    path: Option<String>,
+    muteme: Option<MuteMeSettings>,
+    pulse: Option<PulseSettings>,
+    alsa: Option<AlsaSettings>,
+    gestures: Option<GestureSettings>,
+}
+impl Default for ButtonSettings {
+    fn default() -> Self {
+        Self {
+            serial: None,
+            path: None,
+            muteme: None,
+            pulse: None,
+            alsa: None,
+            gestures: None,
+        }
+    }
+}
+impl ButtonSettings {
+    fn selector(&self) -> DeviceSelector {
+        DeviceSelector {
+            serial: self.serial.clone(),
+            path: self.path.clone(),
         }
     }
 }
@@ -40,6 +129,9 @@ struct Settings {
     main: MainSettings,
     muteme: MuteMeSettings,
     pulse: PulseSettings,
+    alsa: AlsaSettings,
+    gestures: GestureSettings,
+    buttons: Vec<ButtonSettings>,
 }
 impl Default for Settings {
     fn default() -> Self {
@@ -47,6 +139,9 @@ impl Default for Settings {
             main: MainSettings::default(),
             muteme: MuteMeSettings::default(),
             pulse: PulseSettings::default(),
+            alsa: AlsaSettings::default(),
+            gestures: GestureSettings::default(),
+            buttons: Vec::new(),
         }
     }
 }
@@ -55,20 +150,7 @@ impl Settings {
         let mut s = Config::default();
         let defaults = Config::try_from(&Settings::default())?;
         s.merge(defaults)?;
-        let config_file = match arg_matches.value_of("config_file") {
-            Some(file_name) => Some(file_name),
-            None => {
-                if Path::new("mutebtn.toml").is_file() {
-                    Some("mutebtn.toml")
-                } else if Path::new("/etc/mutebtn.toml").is_file() {
-                    Some("/etc/mutebtn.toml")
-                } else {
-                    None
-                }
-            },
-        };
-        if let Some(file_name) = config_file {
-            println!("Using configuration file {}", file_name);
+        if let Some(file_name) = config_file_name(arg_matches) {
             s.merge(File::with_name(file_name))?;
         }
         for settings_key in vec!["muted_color", "unmuted_color", "operation_mode"] {
@@ -81,26 +163,140 @@ impl Settings {
     }
 }
 
-fn main() -> Result<(), HidError> {
+fn config_file_name<'a>(arg_matches: &'a ArgMatches) -> Option<&'a str> {
+    match arg_matches.value_of("config_file") {
+        Some(file_name) => Some(file_name),
+        None => {
+            if Path::new("mutebtn.toml").is_file() {
+                Some("mutebtn.toml")
+            } else if Path::new("/etc/mutebtn.toml").is_file() {
+                Some("/etc/mutebtn.toml")
+            } else {
+                None
+            }
+        },
+    }
+}
+
+/// Arguments for running the daemon. These are also accepted without the `daemon`
+/// subcommand.
+fn daemon_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("muted_color")
+            .long("muted-color")
+            .takes_value(true)
+            .default_value("red")
+            .possible_values(&COLORS)
+            .help("Sets the color when muted"),
+        Arg::with_name("unmuted_color")
+            .long("unmuted-color")
+            .takes_value(true)
+            .default_value("green")
+            .possible_values(&COLORS)
+            .help("Sets the color when not muted"),
+        Arg::with_name("operation_mode")
+            .short("m")
+            .long("mode")
+            .takes_value(true)
+            .default_value("toggle")
+            .possible_values(&MODES)
+            .help("Sets the operation mode"),
+    ]
+}
+
+fn main() {
     let app = clap_app!(mutebtn =>
         (version: "0.2.0")
         (author: "Matthias Erll <matthias@erll.de>")
         (about: "Connects the MuteMe Button")
-        (@arg config_file: -c --config +takes_value
+        (@arg config_file: -c --config +takes_value +global
          "Sets a configuration file name (optional - default is ./mutebtn or /etc/mutebtn)")
-        (@arg muted_color: --("muted-color") +takes_value
-         default_value[red] possible_value[red green blue yellow cyan purple white nocolor]
-         "Sets the color when muted")
-        (@arg unmuted_color: --("unmuted-color") +takes_value
-         default_value[green] possible_value[red green blue yellow cyan purple white nocolor]
-         "Sets the color when not muted")
-        (@arg operation_mode: -m --mode +takes_value
-         default_value[toggle] possible_value[toggle pushtotalk]
-         "Sets the operation mode")
+        (args: &daemon_args())
+        (@subcommand daemon =>
+         (about: "Runs the daemon (default if no subcommand is given)")
+         (args: &daemon_args()))
+        (@subcommand ctl =>
+         (about: "Sends a command to the running daemon")
+         (@setting SubcommandRequiredElseHelp)
+         (@arg socket_path: -s --socket +takes_value
+          "Sets the path of the control socket (optional - default is from the configuration)")
+         (@subcommand mute =>
+          (about: "Mutes all buttons"))
+         (@subcommand unmute =>
+          (about: "Unmutes all buttons"))
+         (@subcommand toggle =>
+          (about: "Toggles the mute state of all buttons"))
+         (@subcommand deafen =>
+          (about: "Mutes the microphone and the speakers"))
+         (@subcommand undeafen =>
+          (about: "Unmutes the speakers and restores the previous microphone state"))
+         (@subcommand toggle_deafen =>
+          (name: "toggle-deafen")
+          (about: "Toggles the deafened state of all buttons"))
+         (@subcommand status =>
+          (about: "Prints the current mute state and operation mode"))
+         (@subcommand set_color =>
+          (name: "set-color")
+          (about: "Sets the color for the muted or unmuted state")
+          (@arg state: +required possible_value[muted unmuted] "State to change the color for")
+          (@arg color: +required possible_values(&COLORS) "New color"))
+         (@subcommand set_mode =>
+          (name: "set-mode")
+          (about: "Sets the operation mode")
+          (@arg mode: +required possible_values(&MODES) "New operation mode")))
     );
     let matches = app.get_matches();
+    match matches.subcommand() {
+        ("ctl", Some(ctl_matches)) => run_ctl(ctl_matches),
+        ("daemon", Some(daemon_matches)) => run_daemon(daemon_matches),
+        _ => run_daemon(&matches),
+    }
+}
+
+fn run_ctl(matches: &ArgMatches) {
+    let socket_path = match matches.value_of("socket_path") {
+        Some(path) => PathBuf::from(path),
+        None => match Settings::new(matches) {
+            Ok(settings) => match settings.main.socket_path {
+                Some(path) => PathBuf::from(path),
+                None => socket::default_path(),
+            },
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            },
+        },
+    };
+    let command = match matches.subcommand() {
+        ("set-color", Some(m)) => format!(
+            "set-color {} {}",
+            m.value_of("state").unwrap(),
+            m.value_of("color").unwrap()
+        ),
+        ("set-mode", Some(m)) => format!("set-mode {}", m.value_of("mode").unwrap()),
+        (name, _) => String::from(name),
+    };
+    match socket::send_command(&socket_path, &command) {
+        Ok(response) => {
+            if response.starts_with("error:") {
+                eprintln!("{}", response);
+                process::exit(1);
+            }
+            println!("{}", response);
+        },
+        Err(err) => {
+            eprintln!("Failed to connect to {}: {}", socket_path.display(), err);
+            process::exit(1);
+        },
+    }
+}
+
+fn run_daemon(matches: &ArgMatches) {
+    if let Some(file_name) = config_file_name(matches) {
+        println!("Using configuration file {}", file_name);
+    }
     let settings;
-    match Settings::new(&matches) {
+    match Settings::new(matches) {
         Ok(s) => settings = s,
         Err(err) => {
             println!("{}", err);
@@ -108,302 +304,497 @@ fn main() -> Result<(), HidError> {
         },
     }
     println!("{:?}", &settings);
+    if let Err(err) = settings.main.audio_backend.check_enabled() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
 
-    let (ctrl_sender, ctrl_receiver) = unbounded();
-    let (int_sender, int_receiver) = unbounded();
     let (exec_sender, exec_receiver) = unbounded();
-    let (audio_sender, audio_receiver) = unbounded();
 
-    let pulse_settings = settings.pulse;
-    let mute_on_startup = settings.main.mute_on_startup.clone();
-    let audio_ctrl_sender = ctrl_sender.clone();
-    let audio_thread = thread::spawn(move || -> () {
-        let mut terminated = false;
-        let mut pulse_control = PulseControl::new(pulse_settings);
-        if let Some(muted) = mute_on_startup {
-            pulse_control.set_muted(muted);
+    // Buttons without a separate entry in `buttons` share the main state in group 0.
+    let mut groups = vec![(
+        settings.muteme.clone(),
+        settings.pulse.clone(),
+        settings.alsa.clone(),
+        settings.gestures.clone(),
+    )];
+    let mut selectors = Vec::new();
+    for button in &settings.buttons {
+        let muteme_settings = button.muteme.clone().unwrap_or(settings.muteme.clone());
+        let pulse_settings = button.pulse.clone().unwrap_or(settings.pulse.clone());
+        let alsa_settings = button.alsa.clone().unwrap_or(settings.alsa.clone());
+        let gesture_settings = button.gestures.clone().unwrap_or(settings.gestures.clone());
+        groups.push((
+            muteme_settings,
+            pulse_settings,
+            alsa_settings,
+            gesture_settings,
+        ));
+        selectors.push(button.selector());
+    }
+
+    let mut ctrl_senders = Vec::new();
+    let mut audio_senders = Vec::new();
+    let mut threads = Vec::new();
+    for (group, (muteme_settings, pulse_settings, alsa_settings, gesture_settings)) in
+        groups.into_iter().enumerate()
+    {
+        let (ctrl_sender, ctrl_receiver) = unbounded();
+        let (audio_sender, audio_receiver) = unbounded();
+        threads.push(spawn_audio_thread(
+            settings.main.audio_backend,
+            pulse_settings,
+            alsa_settings,
+            settings.main.mute_on_startup,
+            ctrl_sender.clone(),
+            audio_receiver,
+        ));
+        if let AudioBackend::Pulse = settings.main.audio_backend {
+            pulse::spawn_source_monitor(audio_sender.clone());
         }
-        while !terminated {
-            let res = audio_receiver.recv();
-            match res {
-                Ok(AudioMessage::GetMuteStatus) => {
-                    let is_muted = pulse_control.is_muted();
-                    audio_ctrl_sender
-                        .send(ControlMessage::PublishMuteStatus(is_muted))
-                        .unwrap_or(());
-                },
-                Ok(AudioMessage::SetMuteStatus(new_state)) => {
-                    pulse_control.set_muted(new_state);
-                },
-                Ok(AudioMessage::Terminate) => terminated = true,
-                Err(RecvError) => terminated = true,
+        threads.push(spawn_control_thread(
+            group,
+            muteme_settings,
+            gesture_settings,
+            ctrl_receiver,
+            exec_sender.clone(),
+            audio_sender.clone(),
+        ));
+        ctrl_senders.push(ctrl_sender);
+        audio_senders.push(audio_sender);
+    }
+
+    let socket_path = match &settings.main.socket_path {
+        Some(path) => PathBuf::from(path),
+        None => socket::default_path(),
+    };
+    if let Err(err) = socket::spawn_server(&socket_path, ctrl_senders.clone()) {
+        println!("Failed to open control socket: {}", err);
+    }
+    #[cfg(feature = "dbus-service")]
+    {
+        if settings.main.dbus_service {
+            dbus_service::spawn_service(ctrl_senders.clone());
+        }
+    }
+
+    let exec_ctrl_senders = ctrl_senders.clone();
+    let device_exec_sender = exec_sender.clone();
+    let exec_thread = thread::spawn(move || {
+        // The scanner cannot be moved between threads, so it is created here.
+        let scanner = MuteMeScanner::new().expect("Failed to initialize HID API");
+        run_devices(
+            scanner,
+            &selectors,
+            &exec_receiver,
+            &exec_ctrl_senders,
+            &device_exec_sender,
+        );
+    });
+
+    let mut signals = Signals::new(&[SIGINT, SIGTERM]).unwrap();
+    let handle = signals.handle();
+    thread::spawn(move || {
+        for sig in signals.forever() {
+            println!("Received signal {:?}", sig);
+            for ctrl_sender in &ctrl_senders {
+                ctrl_sender.send(ControlMessage::Terminate).unwrap_or(());
+            }
+            exec_sender.send(ExecMessage::Terminate).unwrap_or(());
+            for audio_sender in &audio_senders {
+                audio_sender.send(AudioMessage::Terminate).unwrap_or(());
             }
         }
     });
 
-    let mut muteme_settings = settings.muteme;
-    let ctrl_exec_sender = exec_sender.clone();
-    let ctrl_audio_sender = audio_sender.clone();
-    let ctrl_self_sender = ctrl_sender.clone();
-    let ctrl_thread = thread::spawn(move || -> () {
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    exec_thread.join().unwrap();
+    fs::remove_file(&socket_path).unwrap_or(());
+    handle.close();
+}
+
+fn spawn_audio_thread(
+    audio_backend: AudioBackend,
+    pulse_settings: PulseSettings,
+    alsa_settings: AlsaSettings,
+    mute_on_startup: Option<bool>,
+    ctrl_sender: Sender<ControlMessage>,
+    audio_receiver: Receiver<AudioMessage>,
+) -> JoinHandle<()> {
+    thread::spawn(move || -> () {
         let mut terminated = false;
-        let mut is_muted = false;
-        let mut transition = false;
-        ctrl_audio_sender
-            .send(AudioMessage::GetMuteStatus)
-            .unwrap_or(());
-
-        let mut last_touch: Option<Instant> = None;
-        let mut second_touch = false;
-        let double_tap_duration_1 =
-            Duration::from_millis(muteme_settings.double_tap_duration_1.into());
-        let double_tap_duration_2 =
-            Duration::from_millis(muteme_settings.double_tap_duration_2.into());
+        let mut audio = AudioConnection {
+            audio_control: None,
+            audio_backend,
+            pulse_settings,
+            alsa_settings,
+            failed: false,
+            ctrl_sender: ctrl_sender.clone(),
+        };
+        if let Some(muted) = mute_on_startup {
+            audio.run(|control| control.set_muted(muted));
+        }
+        let mut last_state = mute_on_startup;
         while !terminated {
-            let res = ctrl_receiver.recv_timeout(Duration::from_secs(5));
+            // Wait only until the next step while a fade is running.
+            let fade_step = audio
+                .audio_control
+                .as_mut()
+                .and_then(|control| control.continue_fade());
+            let res = match fade_step {
+                Some(timeout) => audio_receiver.recv_timeout(timeout),
+                None => audio_receiver
+                    .recv()
+                    .map_err(|RecvError| RecvTimeoutError::Disconnected),
+            };
             match res {
-                Ok(ControlMessage::PublishMuteStatus(state)) => {
-                    if state != is_muted {
-                        is_muted = state;
-                        transition = false;
+                Ok(AudioMessage::GetMuteStatus) => {
+                    if let Some(Some(is_muted)) = audio.run(|control| control.is_muted()) {
+                        last_state = Some(is_muted);
+                        ctrl_sender
+                            .send(ControlMessage::PublishMuteStatus(is_muted))
+                            .unwrap_or(());
                     }
                 },
-                Ok(ControlMessage::SetColor(mute_state, color)) => {
-                    if mute_state {
-                        muteme_settings.muted_color = color;
-                    } else {
-                        muteme_settings.unmuted_color = color;
+                Ok(AudioMessage::SetMuteStatus(new_state)) => {
+                    if audio.run(|control| control.set_muted(new_state)).is_some() {
+                        last_state = Some(new_state);
                     }
-                    transition = false;
                 },
-                Ok(ControlMessage::SetMode(new_mode)) => {
-                    muteme_settings.operation_mode = new_mode;
-                    is_muted = true;
-                    transition = false;
+                Ok(AudioMessage::SetDeafenStatus(deafened)) => {
+                    audio.run(|control| control.set_deafened(deafened));
                 },
-                Ok(ControlMessage::Event(event)) => {
-                    let new_state;
-                    match event {
-                        DeviceEvent::Touch => {
-                            println!("Touch event");
-                            match muteme_settings.operation_mode {
-                                OperationMode::PushToTalk => new_state = false,
-                                OperationMode::Toggle => new_state = is_muted,
-                                OperationMode::Hybrid => {
-                                    if is_muted {
-                                        new_state = false;
-                                    } else {
-                                        new_state = is_muted;
-                                    }
-                                    match last_touch {
-                                        Some(t) => {
-                                            let duration = Instant::now().duration_since(t);
-                                            println!(
-                                                "Intitial - Duration since last touch: {:?}",
-                                                duration
-                                            );
-                                            second_touch = duration < double_tap_duration_1;
-                                        },
-                                        None => {
-                                            second_touch = false;
-                                        },
-                                    }
-                                    last_touch = Some(Instant::now());
-                                },
-                            }
-                        },
-                        DeviceEvent::Release => {
-                            println!("Release event");
-                            match muteme_settings.operation_mode {
-                                OperationMode::PushToTalk => new_state = true,
-                                OperationMode::Toggle => new_state = !is_muted,
-                                OperationMode::Hybrid => {
-                                    if second_touch {
-                                        match last_touch {
-                                            Some(t) => {
-                                                let duration = Instant::now().duration_since(t);
-                                                println!("Release on 2nd touch - Duration since last touch: {:?}", duration);
-                                                if duration < double_tap_duration_2 {
-                                                    new_state = false;
-                                                } else {
-                                                    new_state = true;
-                                                    second_touch = false;
-                                                }
-                                            },
-                                            None => {
-                                                new_state = true;
-                                            },
-                                        }
-                                    } else {
-                                        new_state = true;
-                                    }
-                                },
-                            }
-                        },
-                    };
-                    if is_muted != new_state {
-                        is_muted = new_state;
-                        transition = false;
+                Ok(AudioMessage::SourcesChanged) => {
+                    // Streams that start while muted are muted as well, instead of being
+                    // taken as an unmute.
+                    if last_state == Some(true) {
+                        audio.run(|control| control.mute_new_streams());
+                    }
+                    // Only publish changes made outside of this app, e.g. by a mixer. Without
+                    // any streams, the last state is kept.
+                    if let Some(Some(is_muted)) = audio.run(|control| control.is_muted()) {
+                        if last_state != Some(is_muted) {
+                            println!("Mute status changed externally");
+                            last_state = Some(is_muted);
+                            ctrl_sender
+                                .send(ControlMessage::PublishMuteStatus(is_muted))
+                                .unwrap_or(());
+                        }
                     }
                 },
-                Ok(ControlMessage::Continue) => {},
-                Ok(ControlMessage::Terminate) => terminated = true,
-                Err(RecvTimeoutError::Timeout) => {
-                    println!("Sending keepalive");
-                    transition = false;
-                },
+                Ok(AudioMessage::Terminate) => terminated = true,
                 Err(RecvTimeoutError::Disconnected) => terminated = true,
+                Err(RecvTimeoutError::Timeout) => {},
             }
+        }
+    })
+}
 
-            let current_color = if is_muted {
-                &muteme_settings.muted_color
-            } else {
-                &muteme_settings.unmuted_color
-            };
-            let effect: u8;
-            if transition {
-                effect = 0x40;
-                transition = false;
-                ctrl_audio_sender
-                    .send(AudioMessage::SetMuteStatus(is_muted))
-                    .unwrap_or(());
-            } else {
-                effect = 0x00;
-                let sub_thread_sender = ctrl_self_sender.clone();
-                thread::spawn(move || {
-                    thread::sleep(Duration::from_millis(100));
-                    sub_thread_sender
-                        .send(ControlMessage::Continue)
-                        .unwrap_or(());
-                });
-                transition = true;
-            }
-            let color_value = current_color.get_byte_value() + effect;
-            ctrl_exec_sender
-                .send(ExecMessage::SetReport(color_value))
+/// Audio control of the audio thread, which is connected on demand so that the connection is
+/// retried after the audio server was unavailable.
+struct AudioConnection {
+    audio_control: Option<Box<dyn Mute>>,
+    audio_backend: AudioBackend,
+    pulse_settings: PulseSettings,
+    alsa_settings: AlsaSettings,
+    failed: bool,
+    ctrl_sender: Sender<ControlMessage>,
+}
+impl AudioConnection {
+    /// Runs an operation on the audio control, and tells the control thread when operations
+    /// start or stop failing. Returns the result of the operation if it succeeded.
+    fn run<T>(
+        &mut self,
+        operation: impl FnOnce(&mut Box<dyn Mute>) -> Result<T, AudioError>,
+    ) -> Option<T> {
+        let result = self.connect().and_then(operation);
+        let value = match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                println!("{}", err);
+                if let AudioError::Unavailable(_) = err {
+                    self.audio_control = None;
+                }
+                None
+            },
+        };
+        if self.failed != value.is_none() {
+            self.failed = value.is_none();
+            self.ctrl_sender
+                .send(ControlMessage::PublishAudioError(self.failed))
                 .unwrap_or(());
         }
-    });
-    let int_exec_sender = exec_sender.clone();
-    let int_thread = thread::spawn(move || {
-        let mut terminated = false;
-        while !terminated {
-            int_exec_sender
-                .send(ExecMessage::ReadInterrupt)
-                .unwrap_or(());
-            let res = int_receiver.recv_timeout(Duration::from_millis(50));
-            match res {
-                Ok(IntMessage::Terminate) => terminated = true,
-                Err(RecvTimeoutError::Disconnected) => terminated = true,
-                Err(RecvTimeoutError::Timeout) => continue,
-            }
+        value
+    }
+
+    fn connect(&mut self) -> Result<&mut Box<dyn Mute>, AudioError> {
+        if self.audio_control.is_none() {
+            self.audio_control = Some(create_audio_control(
+                self.audio_backend,
+                self.pulse_settings.clone(),
+                self.alsa_settings.clone(),
+            )?);
         }
-    });
-    let exec_ctrl_sender = ctrl_sender.clone();
-    let exec_thread = thread::spawn(move || {
-        let api = hidapi::HidApi::new().unwrap();
-        let device = api
-            .open(muteme::DEVICE_VID, muteme::DEVICE_PID)
-            .expect("Failed to open USB device");
-        device
-            .set_blocking_mode(false)
-            .expect("Failed to set device to non-blocking mode");
+        Ok(self.audio_control.as_mut().unwrap())
+    }
+}
 
-        let mut terminated = false;
-        let mut state = 0;
+#[cfg_attr(not(feature = "alsa"), allow(unused_variables))]
+fn create_audio_control(
+    audio_backend: AudioBackend,
+    pulse_settings: PulseSettings,
+    alsa_settings: AlsaSettings,
+) -> Result<Box<dyn Mute>, AudioError> {
+    match audio_backend {
+        AudioBackend::Pulse => Ok(Box::new(PulseControl::new(pulse_settings)?)),
+        #[cfg(feature = "pipewire")]
+        AudioBackend::PipeWire => match PipeWireControl::new(pulse_settings) {
+            Ok(control) => Ok(Box::new(control)),
+            Err(err) => Err(AudioError::Unavailable(format!(
+                "Failed to connect to PipeWire: {}",
+                err
+            ))),
+        },
+        #[cfg(not(feature = "pipewire"))]
+        AudioBackend::PipeWire => unreachable!("Backend is checked on startup"),
+        #[cfg(feature = "alsa")]
+        AudioBackend::Alsa => Ok(Box::new(AlsaControl::new(alsa_settings))),
+        #[cfg(not(feature = "alsa"))]
+        AudioBackend::Alsa => unreachable!("Backend is checked on startup"),
+    }
+}
 
-        while !terminated {
-            loop {
-                let data = read_interrupt(&device);
-                match data {
-                    Some(new_state @ 1..=2) if state != new_state => {
-                        state = new_state;
-                        if state == 1 {
-                            exec_ctrl_sender
-                                .send(ControlMessage::Event(DeviceEvent::Touch))
-                                .unwrap_or(());
-                        } else {
-                            exec_ctrl_sender
-                                .send(ControlMessage::Event(DeviceEvent::Release))
-                                .unwrap_or(());
-                        }
+fn spawn_control_thread(
+    group: usize,
+    muteme_settings: MuteMeSettings,
+    gesture_settings: GestureSettings,
+    ctrl_receiver: Receiver<ControlMessage>,
+    exec_sender: Sender<ExecMessage>,
+    audio_sender: Sender<AudioMessage>,
+) -> JoinHandle<()> {
+    thread::spawn(move || -> () {
+        let mut controller = Controller::new(muteme_settings, gesture_settings);
+        let mut subscribers: Vec<Sender<(usize, ControlStatus)>> = Vec::new();
+        let mut last_status: Option<ControlStatus> = None;
+        let mut timers = Timers::default();
+        audio_sender.send(AudioMessage::GetMuteStatus).unwrap_or(());
+
+        loop {
+            let now = Instant::now();
+            let keepalive = now + KEEPALIVE_INTERVAL;
+            let deadline = timers.next().map_or(keepalive, |next| next.min(keepalive));
+            let res = ctrl_receiver.recv_deadline(deadline);
+            let event = match res {
+                Ok(ControlMessage::PublishMuteStatus(state)) => {
+                    Some(ControlEvent::MuteStatusChanged(state))
+                },
+                Ok(ControlMessage::PublishAudioError(failed)) => {
+                    Some(ControlEvent::AudioErrorChanged(failed))
+                },
+                Ok(ControlMessage::SetMuted(state)) => Some(ControlEvent::SetMuted(state)),
+                Ok(ControlMessage::Toggle) => Some(ControlEvent::Toggle),
+                Ok(ControlMessage::SetDeafened(state)) => Some(ControlEvent::SetDeafened(state)),
+                Ok(ControlMessage::ToggleDeafen) => Some(ControlEvent::ToggleDeafen),
+                Ok(ControlMessage::GetStatus(status_sender)) => {
+                    status_sender.send(controller.status()).unwrap_or(());
+                    None
+                },
+                Ok(ControlMessage::Subscribe(status_sender)) => {
+                    subscribers.push(status_sender);
+                    last_status = None;
+                    None
+                },
+                Ok(ControlMessage::SetDeviceConnected(connected)) => {
+                    Some(ControlEvent::SetDeviceConnected(connected))
+                },
+                Ok(ControlMessage::SetColor(mute_state, color)) => {
+                    Some(ControlEvent::SetColor(mute_state, color))
+                },
+                Ok(ControlMessage::SetMode(new_mode)) => Some(ControlEvent::SetMode(new_mode)),
+                Ok(ControlMessage::Event(event)) => Some(ControlEvent::Device(event)),
+                Err(RecvTimeoutError::Timeout) => {
+                    // Due timers are handled together, as the controller checks all of them.
+                    if timers.take_due(Instant::now()) {
+                        Some(ControlEvent::Continue)
+                    } else {
+                        Some(ControlEvent::Keepalive)
+                    }
+                },
+                Ok(ControlMessage::Terminate) | Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let actions = match event {
+                Some(event) => controller.on_event(event, Instant::now()),
+                None => Vec::new(),
+            };
+
+            let status = controller.status();
+            if last_status.as_ref() != Some(&status) {
+                subscribers.retain(|subscriber| subscriber.send((group, status.clone())).is_ok());
+                last_status = Some(status);
+            }
+
+            for action in actions {
+                match action {
+                    Action::SetLight(state) => {
+                        exec_sender
+                            .send(ExecMessage::SetLight(group, state))
+                            .unwrap_or(());
+                    },
+                    Action::SetMuted(muted) => {
+                        audio_sender
+                            .send(AudioMessage::SetMuteStatus(muted))
+                            .unwrap_or(());
                     },
-                    Some(_) => {},
-                    None => break,
+                    Action::SetDeafened(deafened) => {
+                        audio_sender
+                            .send(AudioMessage::SetDeafenStatus(deafened))
+                            .unwrap_or(());
+                    },
+                    Action::RunCommand(command) => run_command(command),
+                    Action::ScheduleContinue(delay) => timers.schedule(Instant::now() + delay),
                 }
-                thread::yield_now();
             }
+        }
+    })
+}
 
-            let res = exec_receiver.recv();
-            match res {
-                Ok(ExecMessage::SetReport(value)) => write_value(&device, value),
-                Ok(ExecMessage::ReadInterrupt) => continue,
-                Ok(ExecMessage::Terminate) => terminated = true,
-                Err(RecvError) => terminated = true,
+/// Runs a shell command in the background and reports its exit status.
+fn run_command(command: String) {
+    thread::spawn(
+        move || match process::Command::new("sh").arg("-c").arg(&command).status() {
+            Ok(status) if !status.success() => println!("Command {} {}", command, status),
+            Ok(_) => {},
+            Err(err) => println!("Could not run command {}: {}", command, err),
+        },
+    );
+}
+
+/// Opens the devices found by the scanner, and sets their lights until terminated. Devices
+/// are assigned to a group by the selectors, or to group 0 if none of them matches.
+fn run_devices<S: DeviceScanner>(
+    mut scanner: S,
+    selectors: &[DeviceSelector],
+    exec_receiver: &Receiver<ExecMessage>,
+    ctrl_senders: &[Sender<ControlMessage>],
+    exec_sender: &Sender<ExecMessage>,
+) {
+    // Open devices, with their group. Dropping the stop sender ends the reader thread.
+    let mut devices: Vec<(S::Device, usize, Sender<()>)> = Vec::new();
+    let mut last_scan: Option<Instant> = None;
+    let mut lights: Vec<Option<LedState>> = vec![None; ctrl_senders.len()];
+    let mut connected = vec![false; ctrl_senders.len()];
+
+    println!("Waiting for devices");
+    let mut terminated = false;
+
+    while !terminated {
+        let rescan = match last_scan {
+            Some(t) => t.elapsed() >= RECONNECT_INTERVAL,
+            None => true,
+        };
+        if rescan {
+            last_scan = Some(Instant::now());
+            match scanner.scan() {
+                Ok(ids) => {
+                    for id in ids {
+                        if devices.iter().any(|(dev, _, _)| dev.id() == &id) {
+                            continue;
+                        }
+                        let group = match selectors.iter().position(|sel| sel.matches(&id)) {
+                            Some(i) => i + 1,
+                            None => 0,
+                        };
+                        match scanner.open(id) {
+                            Ok((mut device, reader)) => {
+                                println!("Device {:?} connected", device.id());
+                                let ready = match &lights[group] {
+                                    Some(state) => set_device_light(&mut device, state),
+                                    None => true,
+                                };
+                                if ready {
+                                    let (stop_sender, stop_receiver) = unbounded();
+                                    spawn_reader_thread(
+                                        device.id().clone(),
+                                        reader,
+                                        stop_receiver,
+                                        ctrl_senders[group].clone(),
+                                        exec_sender.clone(),
+                                    );
+                                    devices.push((device, group, stop_sender));
+                                }
+                            },
+                            Err(err) => println!("{}", err),
+                        }
+                    }
+                },
+                Err(err) => println!("{}", err),
             }
         }
-    });
 
-    let mut signals = Signals::new(&[SIGINT, SIGTERM]).unwrap();
-    let handle = signals.handle();
-    thread::spawn(move || {
-        for sig in signals.forever() {
-            println!("Received signal {:?}", sig);
-            int_sender.send(IntMessage::Terminate).unwrap_or(());
-            ctrl_sender.send(ControlMessage::Terminate).unwrap_or(());
-            exec_sender.send(ExecMessage::Terminate).unwrap_or(());
-            audio_sender.send(AudioMessage::Terminate).unwrap_or(());
+        for (group, group_connected) in connected.iter_mut().enumerate() {
+            let any_open = devices.iter().any(|(_, g, _)| *g == group);
+            if *group_connected != any_open {
+                *group_connected = any_open;
+                ctrl_senders[group]
+                    .send(ControlMessage::SetDeviceConnected(any_open))
+                    .unwrap_or(());
+            }
         }
-    });
 
-    int_thread.join().unwrap();
-    ctrl_thread.join().unwrap();
-    exec_thread.join().unwrap();
-    audio_thread.join().unwrap();
-    handle.close();
-
-    Ok(())
-}
-
-fn write_value(device: &HidDevice, value: u8) {
-    let data = [0x00, value];
-    let mut attempts = 3u8;
-    loop {
-        attempts -= 1;
-        let res = device.write(&data);
+        let next_scan = last_scan.map_or(Instant::now(), |t| t + RECONNECT_INTERVAL);
+        let res = exec_receiver.recv_deadline(next_scan);
         match res {
-            Ok(i) => {
-                println!("Wrote {} bytes", i);
-                break;
+            Ok(ExecMessage::SetLight(group, state)) => {
+                devices
+                    .retain_mut(|(device, g, _)| *g != group || set_device_light(device, &state));
+                lights[group] = Some(state);
             },
-            Err(err) => println!("{}", err),
-        };
-        if attempts > 0 {
-            thread::sleep(Duration::from_millis(10));
-        } else {
-            break;
+            Ok(ExecMessage::Disconnected(id)) => {
+                devices.retain(|(device, _, _)| device.id() != &id);
+            },
+            Ok(ExecMessage::Terminate) => terminated = true,
+            Err(RecvTimeoutError::Disconnected) => terminated = true,
+            Err(RecvTimeoutError::Timeout) => {},
         }
     }
+    // Dropping the stop senders ends the reader threads.
 }
 
-fn read_interrupt(device: &HidDevice) -> Option<u8> {
-    let mut buf = [0u8; 8];
-    let mut attempts = 3u8;
-    loop {
-        attempts -= 1;
-        let res = device.read(&mut buf);
-        match res {
-            Ok(_i @ 0) => return None,
-            Ok(_) => return Some(buf[3]),
-            Err(err) => {
-                println!("{}", err);
-            },
-        }
-        if attempts > 0 {
-            thread::sleep(Duration::from_millis(10));
-        } else {
-            break;
-        }
+/// Sets the light of the device. Returns whether the device is still connected.
+fn set_device_light<D: MuteDevice>(device: &mut D, state: &LedState) -> bool {
+    match device.set_light(state) {
+        Ok(()) => true,
+        Err(err) => {
+            println!("{}", err);
+            false
+        },
     }
-    None
+}
+
+/// Forwards the events of a device as soon as they arrive, until it is disconnected or the
+/// stop sender is dropped.
+fn spawn_reader_thread<R: EventReader + Send + 'static>(
+    id: DeviceId,
+    mut reader: R,
+    stop_receiver: Receiver<()>,
+    ctrl_sender: Sender<ControlMessage>,
+    exec_sender: Sender<ExecMessage>,
+) {
+    thread::spawn(move || {
+        while let Err(TryRecvError::Empty) = stop_receiver.try_recv() {
+            match reader.read_event(READ_TIMEOUT) {
+                Ok(Some(event)) => ctrl_sender.send(ControlMessage::Event(event)).unwrap_or(()),
+                Ok(None) => {},
+                Err(err) => {
+                    println!("{}", err);
+                    exec_sender
+                        .send(ExecMessage::Disconnected(id))
+                        .unwrap_or(());
+                    return;
+                },
+            }
+        }
+    });
 }
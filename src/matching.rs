@@ -0,0 +1,352 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::TryFrom};
+
+/// Shell-style pattern with `*` and `?` wildcards, or a regular expression if enclosed in
+/// slashes, e.g. `/^alsa_input\.usb-/`. Globs have to match the entire text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pattern {
+    source: String,
+    regex: Regex,
+}
+impl Pattern {
+    pub fn is_match(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+}
+impl TryFrom<String> for Pattern {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let expression = if source.len() > 1 && source.starts_with('/') && source.ends_with('/') {
+            source[1..source.len() - 1].to_string()
+        } else {
+            let mut expression = String::from("^");
+            for c in source.chars() {
+                match c {
+                    '*' => expression.push_str(".*"),
+                    '?' => expression.push('.'),
+                    _ => expression.push_str(&regex::escape(&c.to_string())),
+                }
+            }
+            expression.push('$');
+            expression
+        };
+        let regex = Regex::new(&expression).map_err(|err| err.to_string())?;
+        Ok(Self { source, regex })
+    }
+}
+impl From<Pattern> for String {
+    fn from(pattern: Pattern) -> Self {
+        pattern.source
+    }
+}
+
+/// Recording device as seen by the audio backends.
+pub trait SourceInfo {
+    fn name(&self) -> Option<&str>;
+    fn description(&self) -> Option<&str>;
+    fn property(&self, key: &str) -> Option<String>;
+
+    fn is_monitor(&self) -> bool {
+        self.name().map_or(false, |name| name.ends_with(".monitor"))
+    }
+}
+
+/// Matches recording devices by name, description and / or properties, or the current
+/// default device. Entries without any criteria do not match any device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SourceMatch {
+    pub default: bool,
+    pub name: Option<Pattern>,
+    pub description: Option<Pattern>,
+    pub properties: HashMap<String, Pattern>,
+}
+impl Default for SourceMatch {
+    fn default() -> Self {
+        Self {
+            default: false,
+            name: None,
+            description: None,
+            properties: HashMap::new(),
+        }
+    }
+}
+impl SourceMatch {
+    pub fn matches(&self, source: &dyn SourceInfo, default_name: Option<&str>) -> bool {
+        if !self.default
+            && self.name.is_none()
+            && self.description.is_none()
+            && self.properties.is_empty()
+        {
+            return false;
+        }
+        if self.default && (default_name.is_none() || source.name() != default_name) {
+            return false;
+        }
+        let text_matches = |pattern: &Option<Pattern>, text: Option<&str>| match pattern {
+            Some(pattern) => text.map_or(false, |text| pattern.is_match(text)),
+            None => true,
+        };
+        text_matches(&self.name, source.name())
+            && text_matches(&self.description, source.description())
+            && self.properties.iter().all(|(key, pattern)| {
+                source
+                    .property(key)
+                    .map_or(false, |value| pattern.is_match(&value))
+            })
+    }
+}
+
+/// Devices that are left out when muting all devices. Monitors of output devices are
+/// skipped by default, since they are never muted along with the microphones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SourceExclusion {
+    pub skip_monitors: bool,
+    pub names: Vec<Pattern>,
+}
+impl Default for SourceExclusion {
+    fn default() -> Self {
+        Self {
+            skip_monitors: true,
+            names: Vec::new(),
+        }
+    }
+}
+impl SourceExclusion {
+    /// Checks the device name and description against the excluded names.
+    pub fn excludes(&self, source: &dyn SourceInfo) -> bool {
+        if self.skip_monitors && source.is_monitor() {
+            return true;
+        }
+        self.names.iter().any(|pattern| {
+            source.name().map_or(false, |name| pattern.is_match(name))
+                || source
+                    .description()
+                    .map_or(false, |description| pattern.is_match(description))
+        })
+    }
+}
+
+/// Returns all devices matched by the first entry of the fallback list that matches any.
+pub fn select_sources<'a, S, I>(
+    fallbacks: &[SourceMatch],
+    sources: I,
+    default_name: Option<&str>,
+) -> Vec<&'a S>
+where
+    S: SourceInfo,
+    I: IntoIterator<Item = &'a S>,
+{
+    let sources: Vec<&S> = sources.into_iter().collect();
+    for source_match in fallbacks {
+        let selected: Vec<&S> = sources
+            .iter()
+            .copied()
+            .filter(|source| source_match.matches(*source, default_name))
+            .collect();
+        if !selected.is_empty() {
+            return selected;
+        }
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Source {
+        name: &'static str,
+        description: &'static str,
+        properties: Vec<(&'static str, &'static str)>,
+    }
+    impl SourceInfo for Source {
+        fn name(&self) -> Option<&str> {
+            Some(self.name)
+        }
+
+        fn description(&self) -> Option<&str> {
+            Some(self.description)
+        }
+
+        fn property(&self, key: &str) -> Option<String> {
+            self.properties
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    fn source(name: &'static str, description: &'static str) -> Source {
+        Source {
+            name,
+            description,
+            properties: Vec::new(),
+        }
+    }
+
+    fn pattern(source: &str) -> Pattern {
+        Pattern::try_from(source.to_string()).unwrap()
+    }
+
+    fn name_match(name: &str) -> SourceMatch {
+        SourceMatch {
+            name: Some(pattern(name)),
+            ..SourceMatch::default()
+        }
+    }
+
+    #[test]
+    fn glob_escapes_dots() {
+        let p = pattern("alsa_input.usb");
+        assert!(p.is_match("alsa_input.usb"));
+        assert!(!p.is_match("alsa_inputxusb"));
+    }
+
+    #[test]
+    fn glob_wildcards_match_any_characters() {
+        let p = pattern("alsa_input.*");
+        assert!(p.is_match("alsa_input.usb-mic"));
+        assert!(p.is_match("alsa_input."));
+        assert!(!p.is_match("alsa_output.usb"));
+        let p = pattern("hw:?");
+        assert!(p.is_match("hw:1"));
+        assert!(!p.is_match("hw:"));
+        assert!(!p.is_match("hw:10"));
+    }
+
+    #[test]
+    fn glob_matches_entire_text() {
+        let p = pattern("mic");
+        assert!(p.is_match("mic"));
+        assert!(!p.is_match("usb mic"));
+        assert!(!p.is_match("mic 2"));
+    }
+
+    #[test]
+    fn slashes_enclose_regex() {
+        let p = pattern("/[Ll]oopback/");
+        assert!(p.is_match("Loopback of sink"));
+        assert!(p.is_match("my loopback"));
+        assert!(!p.is_match("Microphone"));
+        // A single slash is a glob.
+        assert!(pattern("/").is_match("/"));
+    }
+
+    #[test]
+    fn invalid_regex_is_rejected() {
+        assert!(Pattern::try_from(String::from("/[unclosed/")).is_err());
+        assert!(Pattern::try_from(String::from("[unclosed")).is_ok());
+    }
+
+    #[test]
+    fn match_without_criteria_matches_nothing() {
+        let mic = source("mic", "Microphone");
+        assert!(!SourceMatch::default().matches(&mic, Some("mic")));
+    }
+
+    #[test]
+    fn match_requires_all_criteria() {
+        let mic = source("alsa_input.usb", "USB Microphone");
+        let source_match = SourceMatch {
+            description: Some(pattern("USB *")),
+            ..name_match("alsa_input.*")
+        };
+        assert!(source_match.matches(&mic, None));
+        let source_match = SourceMatch {
+            description: Some(pattern("Webcam*")),
+            ..name_match("alsa_input.*")
+        };
+        assert!(!source_match.matches(&mic, None));
+    }
+
+    #[test]
+    fn match_checks_properties() {
+        let mic = Source {
+            properties: vec![("device.bus", "usb"), ("device.vendor.id", "046d")],
+            ..source("mic", "Microphone")
+        };
+        let mut properties = HashMap::new();
+        properties.insert(String::from("device.bus"), pattern("usb"));
+        let source_match = SourceMatch {
+            properties,
+            ..SourceMatch::default()
+        };
+        assert!(source_match.matches(&mic, None));
+
+        let mut properties = HashMap::new();
+        properties.insert(String::from("device.bus"), pattern("pci"));
+        let source_match = SourceMatch {
+            properties,
+            ..SourceMatch::default()
+        };
+        assert!(!source_match.matches(&mic, None));
+
+        let mut properties = HashMap::new();
+        properties.insert(String::from("device.serial"), pattern("*"));
+        let source_match = SourceMatch {
+            properties,
+            ..SourceMatch::default()
+        };
+        assert!(!source_match.matches(&mic, None));
+    }
+
+    #[test]
+    fn match_default_requires_default_device() {
+        let mic = source("mic", "Microphone");
+        let source_match = SourceMatch {
+            default: true,
+            ..SourceMatch::default()
+        };
+        assert!(source_match.matches(&mic, Some("mic")));
+        assert!(!source_match.matches(&mic, Some("other")));
+        assert!(!source_match.matches(&mic, None));
+    }
+
+    #[test]
+    fn select_sources_uses_first_matching_fallback() {
+        let sources = vec![
+            source("usb-mic-1", "USB Microphone"),
+            source("usb-mic-2", "USB Microphone"),
+            source("builtin", "Built-in Audio"),
+        ];
+        let fallbacks = vec![
+            name_match("headset"),
+            name_match("usb-mic-*"),
+            name_match("builtin"),
+        ];
+        let names: Vec<&str> = select_sources(&fallbacks, &sources, None)
+            .iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["usb-mic-1", "usb-mic-2"]);
+
+        let names: Vec<&str> = select_sources(&fallbacks[2..], &sources, None)
+            .iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["builtin"]);
+    }
+
+    #[test]
+    fn select_sources_returns_nothing_without_match() {
+        let sources = vec![source("builtin", "Built-in Audio")];
+        assert!(select_sources(&[name_match("headset")], &sources, None).is_empty());
+        assert!(select_sources(&[], &sources, None).is_empty());
+    }
+
+    #[test]
+    fn exclusion_skips_monitors_and_names() {
+        let exclusion = SourceExclusion {
+            names: vec![pattern("/[Ll]oopback/")],
+            ..SourceExclusion::default()
+        };
+        assert!(exclusion.excludes(&source("sink.monitor", "Monitor of Sink")));
+        assert!(exclusion.excludes(&source("loop", "Loopback of Sink")));
+        assert!(!exclusion.excludes(&source("mic", "Microphone")));
+    }
+}
@@ -1,79 +1,440 @@
+use crossbeam_channel::Sender;
+use hidapi::{HidApi, HidDevice, HidError};
 use serde::{Deserialize, Serialize};
+use std::{
+    ffi::CString,
+    fmt,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::device::{
+    Color, DeviceError, DeviceEvent, DeviceId, DeviceScanner, EventReader, LedEffect, LedState,
+    MuteDevice,
+};
+use crate::gestures::GestureAction;
 
 pub const DEVICE_VID: u16 = 0x20a0;
 pub const DEVICE_PID: u16 = 0x42da;
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum Color {
-    Red,
-    Green,
-    Blue,
-    Yellow,
-    Cyan,
-    Purple,
-    White,
-    NoColor,
-}
-impl Color {
-    pub fn get_byte_value(&self) -> u8 {
-        match self {
-            Self::Red => 0x01,
-            Self::Green => 0x02,
-            Self::Blue => 0x04,
-            Self::Yellow => 0x03,
-            Self::Cyan => 0x06,
-            Self::Purple => 0x05,
-            Self::White => 0x07,
-            Self::NoColor => 0x00,
-        }
-    }
-}
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum OperationMode {
     Toggle,
     PushToTalk,
+    PushToMute,
     Hybrid,
 }
-#[derive(Debug)]
-pub enum DeviceEvent {
-    Touch,
-    Release,
+impl FromStr for OperationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toggle" => Ok(Self::Toggle),
+            "pushtotalk" => Ok(Self::PushToTalk),
+            "pushtomute" => Ok(Self::PushToMute),
+            "hybrid" => Ok(Self::Hybrid),
+            _ => Err(format!("Invalid operation mode: {}", s)),
+        }
+    }
+}
+impl fmt::Display for OperationMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Toggle => "toggle",
+            Self::PushToTalk => "pushtotalk",
+            Self::PushToMute => "pushtomute",
+            Self::Hybrid => "hybrid",
+        };
+        write!(f, "{}", name)
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlStatus {
+    pub muted: bool,
+    pub deafened: bool,
+    pub operation_mode: OperationMode,
+    pub device_connected: bool,
+}
+impl ControlStatus {
+    /// Combines the status of several groups of buttons. They are only reported as muted or
+    /// deafened if all of them are, and as connected if any device is connected. The operation mode
+    /// is taken from the first group.
+    pub fn combine<'a, I>(statuses: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = &'a ControlStatus>,
+    {
+        let mut result: Option<Self> = None;
+        for status in statuses {
+            match &mut result {
+                Some(current) => {
+                    current.muted = current.muted && status.muted;
+                    current.deafened = current.deafened && status.deafened;
+                    current.device_connected = current.device_connected || status.device_connected;
+                },
+                None => result = Some(status.clone()),
+            }
+        }
+        result
+    }
 }
 pub enum ControlMessage {
     PublishMuteStatus(bool),
+    PublishAudioError(bool),
+    SetMuted(bool),
+    Toggle,
+    SetDeafened(bool),
+    ToggleDeafen,
+    GetStatus(Sender<ControlStatus>),
+    Subscribe(Sender<(usize, ControlStatus)>),
+    SetDeviceConnected(bool),
     SetColor(bool, Color),
     SetMode(OperationMode),
-    Continue,
     Event(DeviceEvent),
     Terminate,
 }
-pub enum IntMessage {
-    Terminate,
-}
-pub enum ExecMessage {
-    SetReport(u8),
-    ReadInterrupt,
-    Terminate,
+/// Long-press action of earlier versions, replaced by `long_press` in the gesture settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LongPressAction {
+    None,
+    SwitchMode,
+    Deafen,
+    Command,
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct MuteMeSettings {
     pub muted_color: Color,
     pub unmuted_color: Color,
+    pub deafened_color: Color,
+    pub muted_effect: LedEffect,
+    pub unmuted_effect: LedEffect,
+    pub deafened_effect: LedEffect,
+    pub error_color: Color,
+    pub error_effect: LedEffect,
     pub operation_mode: OperationMode,
     pub double_tap_duration_1: u16,
     pub double_tap_duration_2: u16,
+    pub deafen_on_double_tap: bool,
+    pub long_press_duration: u16,
+    /// Deprecated, only used if the gesture settings do not set `long_press`.
+    pub long_press_action: LongPressAction,
+    pub long_press_command: String,
+    pub mute_hold_delay: u16,
+    pub auto_mute_after: Option<u16>,
+    pub auto_mute_warning: u16,
+    pub auto_mute_effect: LedEffect,
+    pub long_press_color: Color,
 }
 impl Default for MuteMeSettings {
     fn default() -> Self {
         Self {
             muted_color: Color::Red,
             unmuted_color: Color::Green,
+            deafened_color: Color::Blue,
+            muted_effect: LedEffect::Flash,
+            unmuted_effect: LedEffect::Flash,
+            deafened_effect: LedEffect::Flash,
+            error_color: Color::Yellow,
+            error_effect: LedEffect::SlowPulse,
             operation_mode: OperationMode::Toggle,
             double_tap_duration_1: 300,
             double_tap_duration_2: 250,
+            deafen_on_double_tap: false,
+            long_press_duration: 1000,
+            long_press_action: LongPressAction::None,
+            long_press_command: String::new(),
+            mute_hold_delay: 0,
+            auto_mute_after: None,
+            auto_mute_warning: 10,
+            auto_mute_effect: LedEffect::FastPulse,
+            long_press_color: Color::White,
+        }
+    }
+}
+
+impl MuteMeSettings {
+    /// Returns the gesture action of the deprecated `long_press_action`, if it is set.
+    pub fn legacy_long_press(&self) -> Option<GestureAction> {
+        match self.long_press_action {
+            LongPressAction::None => None,
+            LongPressAction::SwitchMode => Some(GestureAction::SwitchProfile),
+            LongPressAction::Deafen => Some(GestureAction::Deafen),
+            LongPressAction::Command => {
+                Some(GestureAction::Command(self.long_press_command.clone()))
+            },
+        }
+    }
+}
+
+/// Finds and opens connected MuteMe devices.
+pub struct MuteMeScanner {
+    api: HidApi,
+}
+
+impl MuteMeScanner {
+    pub fn new() -> Result<Self, DeviceError> {
+        let api = HidApi::new().map_err(|err| DeviceError::Backend(err.to_string()))?;
+        Ok(Self { api })
+    }
+}
+
+impl DeviceScanner for MuteMeScanner {
+    type Device = MuteMeDevice;
+    type Reader = MuteMeReader;
+
+    fn scan(&mut self) -> Result<Vec<DeviceId>, DeviceError> {
+        self.api
+            .refresh_devices()
+            .map_err(|err| DeviceError::Backend(err.to_string()))?;
+        let mut ids = Vec::new();
+        for info in self.api.device_list() {
+            if info.vendor_id() != DEVICE_VID || info.product_id() != DEVICE_PID {
+                continue;
+            }
+            let id = DeviceId {
+                path: info.path().to_string_lossy().into_owned(),
+                serial: info.serial_number().map(String::from),
+            };
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Opens the device twice: The light is written through one handle, while the other one
+    /// blocks on reads in a separate thread. HID devices cannot be shared between threads.
+    fn open(&self, id: DeviceId) -> Result<(MuteMeDevice, MuteMeReader), DeviceError> {
+        let path =
+            CString::new(id.path.as_str()).map_err(|err| DeviceError::Backend(err.to_string()))?;
+        // The device has just been listed, so failures are errors, e.g. missing permissions.
+        let open = || {
+            self.api
+                .open_path(&path)
+                .map_err(|err| DeviceError::Backend(format!("Failed to open {}: {}", id.path, err)))
+        };
+        let device = open()?;
+        let reader = open()?;
+        Ok((
+            MuteMeDevice { id, device },
+            MuteMeReader {
+                device: reader,
+                touched: false,
+            },
+        ))
+    }
+}
+
+/// Handle of an open MuteMe device for setting its light.
+pub struct MuteMeDevice {
+    id: DeviceId,
+    device: HidDevice,
+}
+
+impl MuteDevice for MuteMeDevice {
+    fn id(&self) -> &DeviceId {
+        &self.id
+    }
+
+    fn set_light(&mut self, state: &LedState) -> Result<(), DeviceError> {
+        write_value(&self.device, get_byte_value(state))
+            .map_err(|err| DeviceError::Disconnected(err.to_string()))
+    }
+}
+
+/// Handle of an open MuteMe device for reading its button events.
+pub struct MuteMeReader {
+    device: HidDevice,
+    touched: bool,
+}
+
+impl EventReader for MuteMeReader {
+    fn read_event(&mut self, timeout: Duration) -> Result<Option<DeviceEvent>, DeviceError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let report = read_interrupt(&self.device, remaining)
+                .map_err(|err| DeviceError::Disconnected(err.to_string()))?;
+            let report = match report {
+                Some(report) => report,
+                None => return Ok(None),
+            };
+            // Reports repeat while the state does not change, so only changes are events.
+            match report.touch.is_touched() {
+                Some(true) if !self.touched => {
+                    self.touched = true;
+                    return Ok(Some(DeviceEvent::Touch));
+                },
+                Some(false) if self.touched => {
+                    self.touched = false;
+                    return Ok(Some(DeviceEvent::Release));
+                },
+                _ if remaining > Duration::from_millis(0) => {},
+                _ => return Ok(None),
+            }
         }
     }
 }
+
+/// Composes the light report: The lower bits select the color, the upper bits the effect.
+fn get_byte_value(state: &LedState) -> u8 {
+    let color = match state.color {
+        Color::Red => 0x01,
+        Color::Green => 0x02,
+        Color::Blue => 0x04,
+        Color::Yellow => 0x03,
+        Color::Cyan => 0x06,
+        Color::Purple => 0x05,
+        Color::White => 0x07,
+        Color::NoColor => 0x00,
+    };
+    let effect = match state.effect {
+        LedEffect::Solid => 0x00,
+        LedEffect::Dim => 0x10,
+        LedEffect::FastPulse => 0x20,
+        LedEffect::SlowPulse => 0x30,
+        LedEffect::Flash => 0x40,
+    };
+    color | effect
+}
+
+fn write_value(device: &HidDevice, value: u8) -> Result<(), HidError> {
+    let data = [0x00, value];
+    let mut attempts = 3u8;
+    loop {
+        attempts -= 1;
+        let res = device.write(&data);
+        match res {
+            Ok(i) => {
+                println!("Wrote {} bytes", i);
+                return Ok(());
+            },
+            Err(err) => {
+                println!("{}", err);
+                if attempts == 0 {
+                    return Err(err);
+                }
+            },
+        };
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Waits up to the timeout for the next report that can be decoded, skipping short ones.
+fn read_interrupt(device: &HidDevice, timeout: Duration) -> Result<Option<InputReport>, HidError> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; REPORT_SIZE];
+    let mut attempts = 3u8;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let res = device.read_timeout(&mut buf, remaining.as_millis() as i32);
+        match res {
+            Ok(_i @ 0) => return Ok(None),
+            Ok(i) => match InputReport::decode(&buf[..i]) {
+                Some(report) => return Ok(Some(report)),
+                None => {
+                    // Keep waiting for a complete report until the deadline.
+                    println!("Ignoring short report {:?}", &buf[..i]);
+                    if remaining == Duration::from_millis(0) {
+                        return Ok(None);
+                    }
+                    continue;
+                },
+            },
+            Err(err) => {
+                println!("{}", err);
+                attempts -= 1;
+                if attempts == 0 {
+                    return Err(err);
+                }
+            },
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Size of the input reports of the MuteMe.
+const REPORT_SIZE: usize = 8;
+
+/// Touch code in byte 3 of an input report. Only the codes for touch and release are known,
+/// and other codes are kept as they are until they can be identified.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TouchState {
+    Touched,
+    Released,
+    Other(u8),
+}
+impl TouchState {
+    fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => Self::Touched,
+            0x02 => Self::Released,
+            code => Self::Other(code),
+        }
+    }
+
+    /// Returns whether the button is touched, or `None` if the code is not known.
+    pub fn is_touched(&self) -> Option<bool> {
+        match self {
+            Self::Touched => Some(true),
+            Self::Released => Some(false),
+            Self::Other(_) => None,
+        }
+    }
+}
+
+/// Input report, split into the touch code and the raw bytes. The other bytes have no known
+/// meaning yet, and are kept as they are until they can be decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputReport {
+    pub touch: TouchState,
+    pub data: [u8; REPORT_SIZE],
+}
+impl InputReport {
+    /// Decodes a report, which may be shorter than `REPORT_SIZE` as long as it includes the
+    /// touch code. Returns `None` for shorter reports.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let mut data = [0u8; REPORT_SIZE];
+        let len = bytes.len().min(REPORT_SIZE);
+        data[..len].copy_from_slice(&bytes[..len]);
+        Some(Self {
+            touch: TouchState::from_code(data[3]),
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_all_report_bytes() {
+        let bytes = [0x01, 0x02, 0x03, 0x01, 0x05, 0x06, 0x07, 0x08];
+        let report = InputReport::decode(&bytes).unwrap();
+        assert_eq!(report.data, bytes);
+        assert_eq!(report.touch, TouchState::Touched);
+    }
+
+    #[test]
+    fn decodes_short_reports_with_touch_code() {
+        let report = InputReport::decode(&[0, 0, 0, 0x02]).unwrap();
+        assert_eq!(report.touch, TouchState::Released);
+        assert_eq!(report.data, [0, 0, 0, 0x02, 0, 0, 0, 0]);
+        assert_eq!(InputReport::decode(&[0, 0, 0]), None);
+        assert_eq!(InputReport::decode(&[]), None);
+    }
+
+    #[test]
+    fn other_codes_do_not_tell_touch_state() {
+        let report = InputReport::decode(&[0, 0, 0, 0x10, 0, 0, 0, 0]).unwrap();
+        assert_eq!(report.touch, TouchState::Other(0x10));
+        assert_eq!(report.touch.is_touched(), None);
+    }
+}
@@ -1,25 +1,69 @@
-use pulsectl::controllers::{DeviceControl, SourceController};
+use crossbeam_channel::Sender;
+use libpulse_binding::{
+    context::{
+        subscribe::{Facility, InterestMaskSet},
+        Context, FlagSet, State,
+    },
+    mainloop::standard::{IterateResult, Mainloop},
+    volume::{ChannelVolumes, Volume},
+};
+use pulsectl::controllers::{
+    types::{ApplicationInfo, DeviceInfo},
+    AppControl, DeviceControl, SinkController, SourceController,
+};
 use serde::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    fmt,
+    path::PathBuf,
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::matching::{select_sources, SourceExclusion, SourceInfo, SourceMatch};
+use crate::volume_store::VolumeStore;
+
+const FADE_STEP: Duration = Duration::from_millis(20);
 
 #[derive(Debug)]
 pub enum AudioMessage {
     GetMuteStatus,
     SetMuteStatus(bool),
+    SetDeafenStatus(bool),
+    SourcesChanged,
     Terminate,
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum PulseMuteDevice {
     All,
     Default,
     Selected,
+    Applications,
 }
-#[derive(Debug, Serialize, Deserialize)]
+/// Whether devices are muted with their mute switch, or by setting their volume to zero.
+/// Some apps ignore the mute switch of a device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PulseMuteMethod {
+    Flag,
+    Volume,
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct PulseSettings {
     pub mute_device: PulseMuteDevice,
     pub unmute_device: Option<PulseMuteDevice>,
     pub selected_device_name: String,
+    pub selected_devices: Vec<SourceMatch>,
+    pub selected_applications: Vec<String>,
+    pub exclude: SourceExclusion,
+    pub mute_method: PulseMuteMethod,
+    pub volume_state_file: Option<String>,
+    pub fade_ms: u64,
+    pub deafen_device: PulseMuteDevice,
+    pub deafen_device_name: String,
 }
 impl Default for PulseSettings {
     fn default() -> Self {
@@ -27,127 +71,622 @@ impl Default for PulseSettings {
             mute_device: PulseMuteDevice::All,
             unmute_device: Some(PulseMuteDevice::All),
             selected_device_name: String::from(""),
+            selected_devices: Vec::new(),
+            selected_applications: Vec::new(),
+            exclude: SourceExclusion::default(),
+            mute_method: PulseMuteMethod::Flag,
+            volume_state_file: None,
+            fade_ms: 0,
+            deafen_device: PulseMuteDevice::All,
+            deafen_device_name: String::from(""),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AudioError {
+    /// The audio server cannot be reached. The connection has to be opened again.
+    Unavailable(String),
+    /// The devices to mute could not be found or changed.
+    Failed(String),
+}
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unavailable(message) => write!(f, "Audio server unavailable: {}", message),
+            Self::Failed(message) => write!(f, "{}", message),
         }
     }
 }
 
 pub trait Mute {
-    fn is_muted(&mut self) -> bool;
-    fn set_muted(&mut self, muted: bool) -> ();
+    /// Returns `None` if there is nothing to tell the state from, e.g. while the selected
+    /// applications are not recording.
+    fn is_muted(&mut self) -> Result<Option<bool>, AudioError>;
+    fn set_muted(&mut self, muted: bool) -> Result<(), AudioError>;
+    /// Mutes the recording streams of the selected applications that appeared since they were
+    /// last muted, e.g. when an app starts recording while muted.
+    fn mute_new_streams(&mut self) -> Result<(), AudioError> {
+        Ok(())
+    }
+    /// Continues a volume fade started by `set_muted`, and returns the time until the next
+    /// step, or `None` if no fade is running.
+    fn continue_fade(&mut self) -> Option<Duration> {
+        None
+    }
+    /// Mutes or unmutes the speakers. The microphone is muted separately.
+    fn set_deafened(&mut self, _deafened: bool) -> Result<(), AudioError> {
+        Err(AudioError::Failed(String::from(
+            "Deafen is not supported by this audio backend",
+        )))
+    }
+}
+struct FadeTarget {
+    index: u32,
+    name: String,
+    volumes: ChannelVolumes,
+}
+struct Fade {
+    muted: bool,
+    targets: Vec<FadeTarget>,
+    start: Instant,
+    duration: Duration,
 }
 pub struct PulseControl {
     handler: SourceController,
+    sink_handler: Option<SinkController>,
     settings: PulseSettings,
+    volume_store: VolumeStore,
+    fade: Option<Fade>,
+    // Recording streams of the selected applications that have been muted or unmuted.
+    known_streams: Vec<u32>,
 }
 
 impl PulseControl {
-    pub fn new(settings: PulseSettings) -> Self {
-        let handler = SourceController::create().expect("Failed to get handler");
-        Self { handler, settings }
+    pub fn new(settings: PulseSettings) -> Result<Self, AudioError> {
+        let handler = SourceController::create()
+            .map_err(|_| AudioError::Unavailable(String::from("Failed to get handler")))?;
+        let volume_store = VolumeStore::new(match &settings.volume_state_file {
+            Some(path) => PathBuf::from(path),
+            None => VolumeStore::default_path(),
+        });
+        let sink_handler = match SinkController::create() {
+            Ok(sink_handler) => Some(sink_handler),
+            Err(_) => {
+                println!("Failed to get handler for output devices");
+                None
+            },
+        };
+        Ok(Self {
+            handler,
+            sink_handler,
+            settings,
+            volume_store,
+            fade: None,
+            known_streams: Vec::new(),
+        })
     }
 }
-impl Mute for PulseControl {
-    fn is_muted(&mut self) -> bool {
-        let device = match &self.settings.unmute_device {
-            Some(dev) => dev,
-            None => &self.settings.mute_device,
-        };
+impl PulseControl {
+    /// Returns the devices to mute or unmute.
+    fn find_devices(&mut self, device: &PulseMuteDevice) -> Result<Vec<DeviceInfo>, AudioError> {
         match device {
-            PulseMuteDevice::All => {
-                let devices_res = &self.handler.list_devices();
-                match devices_res {
-                    Ok(devices) => {
-                        for dev in devices {
-                            if !dev.mute {
-                                return false;
-                            }
-                        }
-                    },
-                    Err(_) => {
-                        println!("Could not get list of recording devices");
-                        return false;
-                    },
-                }
-                true
+            PulseMuteDevice::All => match self.handler.list_devices() {
+                Ok(devices) => {
+                    let exclude = &self.settings.exclude;
+                    Ok(devices
+                        .into_iter()
+                        .filter(|dev| !exclude.excludes(dev))
+                        .collect())
+                },
+                Err(_) => Err(unavailable("Could not get list of recording devices")),
             },
             PulseMuteDevice::Default => match self.handler.get_server_info() {
                 Ok(server_info) => match server_info.default_source_name {
-                    Some(device_name) => {
-                        return match &self.handler.get_device_by_name(&device_name) {
-                            Ok(dev) => dev.mute,
-                            Err(_) => {
-                                println!("Failed to find device with default source name");
-                                false
-                            },
-                        };
-                    },
-                    None => {
-                        println!("No default device selected");
-                        false
+                    Some(device_name) => match self.handler.get_device_by_name(&device_name) {
+                        Ok(dev) => Ok(vec![dev]),
+                        Err(_) => Err(failed("Failed to find device with default source name")),
                     },
+                    None => Err(failed("No default device selected")),
                 },
-                Err(_) => {
-                    println!("Failed to get server info");
-                    false
-                },
+                Err(_) => Err(unavailable("Failed to get server info")),
+            },
+            PulseMuteDevice::Selected if !self.settings.selected_devices.is_empty() => {
+                self.find_selected_devices()
             },
             PulseMuteDevice::Selected => {
-                return match &self
+                match self
                     .handler
                     .get_device_by_name(&self.settings.selected_device_name)
                 {
-                    Ok(dev) => dev.mute,
-                    Err(_) => {
-                        println!("Failed to find device with selected source name");
-                        false
-                    },
-                };
+                    Ok(dev) => Ok(vec![dev]),
+                    Err(_) => Err(failed("Failed to find device with selected source name")),
+                }
+            },
+            PulseMuteDevice::Applications => Ok(Vec::new()),
+        }
+    }
+
+    /// Evaluates the fallback list of selected devices.
+    fn find_selected_devices(&mut self) -> Result<Vec<DeviceInfo>, AudioError> {
+        let devices = self
+            .handler
+            .list_devices()
+            .map_err(|_| unavailable("Could not get list of recording devices"))?;
+        let default_name = match self.handler.get_server_info() {
+            Ok(server_info) => server_info.default_source_name,
+            Err(_) => None,
+        };
+        let indices: Vec<u32> = select_sources(
+            &self.settings.selected_devices,
+            &devices,
+            default_name.as_deref(),
+        )
+        .into_iter()
+        .map(|dev| dev.index)
+        .collect();
+        if indices.is_empty() {
+            return Err(failed("Failed to find any of the selected devices"));
+        }
+        Ok(devices
+            .into_iter()
+            .filter(|dev| indices.contains(&dev.index))
+            .collect())
+    }
+
+    fn is_device_muted(&self, dev: &DeviceInfo) -> bool {
+        match self.settings.mute_method {
+            PulseMuteMethod::Flag => dev.mute,
+            PulseMuteMethod::Volume => dev.volume.max() == Volume::MUTED,
+        }
+    }
+
+    fn set_device_muted(&mut self, dev: &DeviceInfo, muted: bool) {
+        match self.settings.mute_method {
+            PulseMuteMethod::Flag => self.handler.set_device_mute_by_index(dev.index, muted),
+            PulseMuteMethod::Volume => self.set_device_volume_muted(dev, muted),
+        }
+    }
+
+    /// Sets the volume to zero, saving the previous volume of each channel to restore it on
+    /// unmute.
+    fn set_device_volume_muted(&mut self, dev: &DeviceInfo, muted: bool) {
+        let name = dev.name.clone().unwrap_or_default();
+        let mut volumes = dev.volume;
+        if muted {
+            if volumes.max() == Volume::MUTED {
+                return;
+            }
+            self.volume_store.set(&name, Some(raw_volumes(&volumes)));
+            volumes.set(volumes.len(), Volume::MUTED);
+        } else {
+            volumes = match self.restored_volumes(dev) {
+                Some(volumes) => volumes,
+                None => return,
+            };
+            self.volume_store.set(&name, None);
+        }
+        self.handler.set_device_volume_by_index(dev.index, &volumes);
+    }
+
+    /// Returns the volumes to restore on unmute if a device is muted by volume. Devices
+    /// without a saved volume are unmuted to 100%.
+    fn restored_volumes(&self, dev: &DeviceInfo) -> Option<ChannelVolumes> {
+        let name = dev.name.clone().unwrap_or_default();
+        let mut volumes = dev.volume;
+        match self.volume_store.get(&name) {
+            Some(saved) if saved.len() == volumes.len() as usize => {
+                for (volume, saved_volume) in volumes.get_mut().iter_mut().zip(saved) {
+                    *volume = Volume(saved_volume);
+                }
+            },
+            Some(saved) => {
+                let max = saved.into_iter().max().unwrap_or(Volume::NORMAL.0);
+                volumes.set(volumes.len(), Volume(max));
             },
+            None if volumes.max() == Volume::MUTED => {
+                volumes.set(volumes.len(), Volume::NORMAL);
+            },
+            None => return None,
         }
+        Some(volumes)
     }
 
-    fn set_muted(&mut self, muted: bool) -> () {
+    /// Starts ramping the volume of the devices down before muting, or up after unmuting.
+    /// The fade is continued in steps by `continue_fade`.
+    fn start_fade(&mut self, devices: Vec<DeviceInfo>, muted: bool) {
+        let mut targets = Vec::new();
+        for dev in devices {
+            let volumes = match self.settings.mute_method {
+                PulseMuteMethod::Flag => {
+                    if !muted {
+                        let mut silent = dev.volume;
+                        silent.set(silent.len(), Volume::MUTED);
+                        self.handler.set_device_volume_by_index(dev.index, &silent);
+                        self.handler.set_device_mute_by_index(dev.index, false);
+                    }
+                    dev.volume
+                },
+                PulseMuteMethod::Volume if muted => {
+                    if dev.volume.max() == Volume::MUTED {
+                        continue;
+                    }
+                    dev.volume
+                },
+                PulseMuteMethod::Volume => match self.restored_volumes(&dev) {
+                    Some(volumes) => volumes,
+                    None => continue,
+                },
+            };
+            targets.push(FadeTarget {
+                index: dev.index,
+                name: dev.name.unwrap_or_default(),
+                volumes,
+            });
+        }
+        self.fade = Some(Fade {
+            muted,
+            targets,
+            start: Instant::now(),
+            duration: Duration::from_millis(self.settings.fade_ms),
+        });
+    }
+
+    /// Sets the final state of a running fade. Devices that are muted by their switch get
+    /// their previous volume back, so they are at the same level when unmuted.
+    fn finish_fade(&mut self) {
+        let fade = match self.fade.take() {
+            Some(fade) => fade,
+            None => return,
+        };
+        for target in fade.targets {
+            match self.settings.mute_method {
+                PulseMuteMethod::Flag => {
+                    if fade.muted {
+                        self.handler.set_device_mute_by_index(target.index, true);
+                    }
+                    self.handler
+                        .set_device_volume_by_index(target.index, &target.volumes);
+                },
+                PulseMuteMethod::Volume if fade.muted => {
+                    let mut silent = target.volumes;
+                    silent.set(silent.len(), Volume::MUTED);
+                    self.volume_store
+                        .set(&target.name, Some(raw_volumes(&target.volumes)));
+                    self.handler
+                        .set_device_volume_by_index(target.index, &silent);
+                },
+                PulseMuteMethod::Volume => {
+                    self.volume_store.set(&target.name, None);
+                    self.handler
+                        .set_device_volume_by_index(target.index, &target.volumes);
+                },
+            }
+        }
+    }
+
+    fn is_selected_application(&self, app: &ApplicationInfo) -> bool {
+        let names = [
+            app.proplist.get_str("application.name"),
+            app.proplist.get_str("application.process.binary"),
+        ];
+        application_matches(&self.settings.selected_applications, &names)
+    }
+
+    fn are_applications_muted(&mut self) -> Result<Option<bool>, AudioError> {
+        let apps = self
+            .handler
+            .list_applications()
+            .map_err(|_| unavailable("Could not get list of recording streams"))?;
+        let selected: Vec<&ApplicationInfo> = apps
+            .iter()
+            .filter(|app| self.is_selected_application(app))
+            .collect();
+        if selected.is_empty() {
+            // Not an error, the applications may just not be recording.
+            println!("No recording streams of selected applications found");
+            return Ok(None);
+        }
+        Ok(Some(selected.iter().all(|app| app.mute)))
+    }
+
+    /// Mutes or unmutes the recording streams of the selected applications. With `new_only`,
+    /// only streams that have not been muted or unmuted before are changed.
+    fn set_applications_muted(&mut self, muted: bool, new_only: bool) -> Result<(), AudioError> {
+        let apps = self
+            .handler
+            .list_applications()
+            .map_err(|_| unavailable("Could not get list of recording streams"))?;
+        let mut result = Ok(());
+        let mut known_streams = Vec::new();
+        for app in apps {
+            if !self.is_selected_application(&app) {
+                continue;
+            }
+            known_streams.push(app.index);
+            if new_only && self.known_streams.contains(&app.index) {
+                continue;
+            }
+            if self.handler.set_app_mute(app.index, muted).is_err() {
+                result = Err(AudioError::Failed(format!(
+                    "Failed to set mute on recording stream {}",
+                    app.index
+                )));
+            }
+        }
+        self.known_streams = known_streams;
+        result
+    }
+}
+impl Mute for PulseControl {
+    fn is_muted(&mut self) -> Result<Option<bool>, AudioError> {
+        if let Some(fade) = &self.fade {
+            return Ok(Some(fade.muted));
+        }
+        let device = match &self.settings.unmute_device {
+            Some(dev) => dev.clone(),
+            None => self.settings.mute_device.clone(),
+        };
+        if let PulseMuteDevice::Applications = device {
+            return self.are_applications_muted();
+        }
+        let devices = self.find_devices(&device)?;
+        Ok(Some(devices.iter().all(|dev| self.is_device_muted(dev))))
+    }
+
+    fn set_muted(&mut self, muted: bool) -> Result<(), AudioError> {
         let device;
         if muted {
-            device = &self.settings.mute_device;
+            device = self.settings.mute_device.clone();
         } else {
             device = match &self.settings.unmute_device {
-                Some(dev) => dev,
-                None => &self.settings.mute_device,
+                Some(dev) => dev.clone(),
+                None => self.settings.mute_device.clone(),
             };
         }
-        match device {
+        // A fade to the same state keeps running, the state is applied again on each keepalive.
+        if self.fade.as_ref().map(|fade| fade.muted) != Some(muted) {
+            self.finish_fade();
+        }
+        let fading_to = self.fade.as_ref().map(|fade| fade.muted);
+        if let PulseMuteDevice::Applications = device {
+            return self.set_applications_muted(muted, false);
+        }
+        let devices: Vec<DeviceInfo> = self
+            .find_devices(&device)?
+            .into_iter()
+            .filter(|dev| needs_change(self.is_device_muted(dev), fading_to, muted))
+            .collect();
+        if devices.is_empty() {
+            return Ok(());
+        }
+        if self.settings.fade_ms > 0 {
+            self.start_fade(devices, muted);
+            return Ok(());
+        }
+        for dev in &devices {
+            self.set_device_muted(dev, muted);
+        }
+        Ok(())
+    }
+
+    fn mute_new_streams(&mut self) -> Result<(), AudioError> {
+        match self.settings.mute_device {
+            PulseMuteDevice::Applications => self.set_applications_muted(true, true),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_deafened(&mut self, deafened: bool) -> Result<(), AudioError> {
+        let sink_handler = match &mut self.sink_handler {
+            Some(sink_handler) => sink_handler,
+            None => return Err(unavailable("Failed to get handler for output devices")),
+        };
+        match self.settings.deafen_device {
             PulseMuteDevice::All => {
-                let devices_res = &self.handler.list_devices();
-                match devices_res {
-                    Ok(devices) => {
-                        for dev in devices {
-                            &self.handler.set_device_mute_by_index(dev.index, muted);
-                        }
-                    },
-                    Err(_) => {
-                        println!("Could not get list of recording devices")
-                    },
+                let devices = sink_handler
+                    .list_devices()
+                    .map_err(|_| unavailable("Could not get list of output devices"))?;
+                for dev in devices {
+                    sink_handler.set_device_mute_by_index(dev.index, deafened);
                 }
             },
-            PulseMuteDevice::Default => match self.handler.get_server_info() {
-                Ok(server_info) => match server_info.default_source_name {
+            PulseMuteDevice::Default => {
+                let server_info = sink_handler
+                    .get_server_info()
+                    .map_err(|_| unavailable("Failed to get server info"))?;
+                match server_info.default_sink_name {
                     Some(device_name) => {
-                        &self.handler.set_device_mute_by_name(&device_name, muted);
-                    },
-                    None => {
-                        println!("No default device selected");
+                        sink_handler.set_device_mute_by_name(&device_name, deafened);
                     },
-                },
-                Err(_) => {
-                    println!("Failed to get server info");
-                },
+                    None => return Err(failed("No default output device selected")),
+                }
             },
             PulseMuteDevice::Selected => {
-                &self
-                    .handler
-                    .set_device_mute_by_name(&self.settings.selected_device_name, muted);
+                sink_handler.set_device_mute_by_name(&self.settings.deafen_device_name, deafened);
+            },
+            PulseMuteDevice::Applications => {
+                return Err(failed("Output devices cannot be selected by application"));
             },
         }
+        Ok(())
+    }
+
+    fn continue_fade(&mut self) -> Option<Duration> {
+        let fade = self.fade.as_ref()?;
+        let elapsed = fade.start.elapsed();
+        if elapsed >= fade.duration {
+            self.finish_fade();
+            return None;
+        }
+        let progress = elapsed.as_secs_f64() / fade.duration.as_secs_f64();
+        let factor = if fade.muted { 1.0 - progress } else { progress };
+        for target in &fade.targets {
+            let mut volumes = target.volumes;
+            for volume in volumes.get_mut() {
+                *volume = Volume((volume.0 as f64 * factor) as u32);
+            }
+            self.handler
+                .set_device_volume_by_index(target.index, &volumes);
+        }
+        Some(FADE_STEP)
+    }
+}
+
+impl Drop for PulseControl {
+    fn drop(&mut self) {
+        // Do not leave devices at a lowered volume.
+        self.finish_fade();
+    }
+}
+
+fn unavailable(message: &str) -> AudioError {
+    AudioError::Unavailable(String::from(message))
+}
+
+fn failed(message: &str) -> AudioError {
+    AudioError::Failed(String::from(message))
+}
+
+/// Returns whether `set_muted` has to change a device. Devices that are in the requested
+/// state already, or are fading to it, are left alone. Fading them again would cut the audio
+/// out and in.
+fn needs_change(device_muted: bool, fading_to: Option<bool>, muted: bool) -> bool {
+    fading_to.unwrap_or(device_muted) != muted
+}
+
+fn raw_volumes(volumes: &ChannelVolumes) -> Vec<u32> {
+    volumes.get().iter().map(|volume| volume.0).collect()
+}
+
+impl SourceInfo for DeviceInfo {
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    fn property(&self, key: &str) -> Option<String> {
+        self.proplist.get_str(key)
+    }
+
+    fn is_monitor(&self) -> bool {
+        self.monitor.is_some()
+            || self
+                .name
+                .as_ref()
+                .map_or(false, |n| n.ends_with(".monitor"))
+    }
+}
+
+/// Checks whether any of an application's names contains one of the selected names,
+/// ignoring case.
+pub fn application_matches(selected: &[String], names: &[Option<String>]) -> bool {
+    names.iter().flatten().any(|name| {
+        let name = name.to_lowercase();
+        selected
+            .iter()
+            .any(|selected_name| name.contains(&selected_name.to_lowercase()))
+    })
+}
+
+/// Watches the PulseAudio server for changes of sources and recording streams, and sends
+/// `SourcesChanged` to the audio thread on each change. Reconnects if the connection to the
+/// server is lost.
+pub fn spawn_source_monitor(audio_sender: Sender<AudioMessage>) {
+    thread::spawn(move || {
+        let disconnected = Rc::new(Cell::new(false));
+        while !disconnected.get() {
+            if let Err(err) = watch_sources(&audio_sender, &disconnected) {
+                println!("{}", err);
+            }
+            if !disconnected.get() {
+                thread::sleep(Duration::from_secs(5));
+            }
+        }
+    });
+}
+
+fn watch_sources(
+    audio_sender: &Sender<AudioMessage>,
+    disconnected: &Rc<Cell<bool>>,
+) -> Result<(), String> {
+    let mut mainloop = Mainloop::new().ok_or("Failed to create PulseAudio main loop")?;
+    let mut context =
+        Context::new(&mainloop, "mutebtn-monitor").ok_or("Failed to create PulseAudio context")?;
+    context
+        .connect(None, FlagSet::NOFLAGS, None)
+        .map_err(|_| "Failed to connect to PulseAudio server")?;
+    loop {
+        if let IterateResult::Err(_) | IterateResult::Quit(_) = mainloop.iterate(true) {
+            return Err(String::from("PulseAudio main loop stopped"));
+        }
+        match context.get_state() {
+            State::Ready => break,
+            State::Failed | State::Terminated => {
+                return Err(String::from("Failed to connect to PulseAudio server"));
+            },
+            _ => {},
+        }
+    }
+
+    let sender = audio_sender.clone();
+    let callback_disconnected = Rc::clone(disconnected);
+    context.set_subscribe_callback(Some(Box::new(move |facility, _operation, _index| {
+        if let Some(Facility::Source) | Some(Facility::SourceOutput) | Some(Facility::Server) =
+            facility
+        {
+            if sender.send(AudioMessage::SourcesChanged).is_err() {
+                callback_disconnected.set(true);
+            }
+        }
+    })));
+    context.subscribe(
+        InterestMaskSet::SOURCE | InterestMaskSet::SOURCE_OUTPUT | InterestMaskSet::SERVER,
+        |_| {},
+    );
+
+    while !disconnected.get() {
+        if let IterateResult::Err(_) | IterateResult::Quit(_) = mainloop.iterate(true) {
+            return Err(String::from("Lost connection to PulseAudio server"));
+        }
+        if let State::Failed | State::Terminated = context.get_state() {
+            return Err(String::from("Lost connection to PulseAudio server"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_unmute_changes_nothing() {
+        // Muted device, first unmute starts the fade.
+        assert!(needs_change(true, None, false));
+        // Unmuted again on keepalive while fading in. With the mute switch, the device is
+        // already unmuted; with the volume, it still counts as muted.
+        assert!(!needs_change(false, Some(false), false));
+        assert!(!needs_change(true, Some(false), false));
+        // Unmuted again after the fade.
+        assert!(!needs_change(false, None, false));
+    }
+
+    #[test]
+    fn repeated_mute_changes_nothing() {
+        assert!(needs_change(false, None, true));
+        assert!(!needs_change(false, Some(true), true));
+        assert!(!needs_change(true, None, true));
+    }
+
+    #[test]
+    fn opposite_state_changes_device() {
+        // A running fade is finished first, so the device state decides.
+        assert!(needs_change(true, None, false));
+        assert!(needs_change(false, None, true));
+        assert!(needs_change(false, Some(true), false));
     }
 }
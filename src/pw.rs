@@ -0,0 +1,464 @@
+use pipewire::{
+    context::Context,
+    core::{Core, PW_ID_CORE},
+    main_loop::MainLoop,
+    metadata::{Metadata, MetadataListener},
+    node::{Node, NodeListener},
+    registry::{self, GlobalObject, Registry},
+    spa::{
+        param::ParamType,
+        pod::{
+            deserialize::PodDeserializer, serialize::PodSerializer, Object, Pod, Property,
+            PropertyFlags, Value,
+        },
+        sys::SPA_PROP_mute,
+        utils::{dict::DictRef, SpaTypes},
+    },
+    types::ObjectType,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    io::Cursor,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::matching::{select_sources, SourceInfo};
+use crate::pulse::{
+    application_matches, AudioError, Mute, PulseMuteDevice, PulseMuteMethod, PulseSettings,
+};
+
+const SOURCE_MEDIA_CLASS: &str = "Audio/Source";
+const STREAM_MEDIA_CLASS: &str = "Stream/Input/Audio";
+const DEFAULT_SOURCE_KEY: &str = "default.audio.source";
+const ROUNDTRIP_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct AudioNode {
+    id: u32,
+    name: String,
+    properties: HashMap<String, String>,
+    app_names: Vec<Option<String>>,
+    node: Node,
+    muted: Rc<Cell<Option<bool>>>,
+    _listener: NodeListener,
+}
+#[derive(Default)]
+struct PipeWireState {
+    sources: HashMap<u32, AudioNode>,
+    streams: HashMap<u32, AudioNode>,
+    default_source: Option<String>,
+    metadata: Option<(Metadata, MetadataListener)>,
+}
+
+/// Mutes PipeWire nodes of the media class `Audio/Source`, or recording streams of selected
+/// applications. Devices are selected in the same way as with PulseAudio; node names are the
+/// source names shown by pipewire-pulse.
+pub struct PipeWireControl {
+    // Proxies and listeners have to be dropped before the connection.
+    state: Rc<RefCell<PipeWireState>>,
+    _registry_listener: registry::Listener,
+    _registry: Rc<Registry>,
+    core: Core,
+    _context: Context,
+    mainloop: MainLoop,
+    settings: PulseSettings,
+    // Recording streams of the selected applications that have been muted or unmuted.
+    known_streams: Vec<u32>,
+}
+
+impl PipeWireControl {
+    pub fn new(settings: PulseSettings) -> Result<Self, String> {
+        if let PulseMuteMethod::Volume = settings.mute_method {
+            println!("Muting by volume is not supported by PipeWire, using the mute switch");
+        }
+        pipewire::init();
+        let mainloop = MainLoop::new(None).map_err(|err| err.to_string())?;
+        let context = Context::new(&mainloop).map_err(|err| err.to_string())?;
+        let core = context.connect(None).map_err(|err| err.to_string())?;
+        let registry = Rc::new(core.get_registry().map_err(|err| err.to_string())?);
+        let state = Rc::new(RefCell::new(PipeWireState::default()));
+
+        let global_registry = Rc::downgrade(&registry);
+        let global_state = Rc::downgrade(&state);
+        let remove_state = Rc::downgrade(&state);
+        let registry_listener = registry
+            .add_listener_local()
+            .global(move |global| {
+                if let (Some(registry), Some(state)) =
+                    (global_registry.upgrade(), global_state.upgrade())
+                {
+                    add_global(&registry, &state, global);
+                }
+            })
+            .global_remove(move |id| {
+                if let Some(state) = remove_state.upgrade() {
+                    let mut state = state.borrow_mut();
+                    state.sources.remove(&id);
+                    state.streams.remove(&id);
+                }
+            })
+            .register();
+
+        let control = Self {
+            state,
+            _registry_listener: registry_listener,
+            _registry: registry,
+            core,
+            _context: context,
+            mainloop,
+            settings,
+            known_streams: Vec::new(),
+        };
+        // The first roundtrip announces all globals, the second one their current props.
+        control.roundtrip().map_err(|err| err.to_string())?;
+        control.roundtrip().map_err(|err| err.to_string())?;
+        Ok(control)
+    }
+
+    /// Processes events until the server has handled all previous requests.
+    fn roundtrip(&self) -> Result<(), AudioError> {
+        let pending = self
+            .core
+            .sync(0)
+            .map_err(|err| AudioError::Unavailable(format!("PipeWire sync failed: {}", err)))?;
+        let done = Rc::new(Cell::new(false));
+        let done_cb = Rc::clone(&done);
+        let _listener = self
+            .core
+            .add_listener_local()
+            .done(move |id, seq| {
+                if id == PW_ID_CORE && seq == pending {
+                    done_cb.set(true);
+                }
+            })
+            .register();
+        let start = Instant::now();
+        while !done.get() && start.elapsed() < ROUNDTRIP_TIMEOUT {
+            self.mainloop.loop_().iterate(Duration::from_millis(100));
+        }
+        if !done.get() {
+            return Err(AudioError::Unavailable(String::from(
+                "PipeWire did not respond",
+            )));
+        }
+        Ok(())
+    }
+
+    fn find_source<'a>(state: &'a PipeWireState, name: &str) -> Option<&'a AudioNode> {
+        state.sources.values().find(|source| source.name == name)
+    }
+
+    fn all_sources<'a>(&self, state: &'a PipeWireState) -> Vec<&'a AudioNode> {
+        state
+            .sources
+            .values()
+            .filter(|source| !self.settings.exclude.excludes(*source))
+            .collect()
+    }
+
+    fn selected_sources<'a>(&self, state: &'a PipeWireState) -> Vec<&'a AudioNode> {
+        select_sources(
+            &self.settings.selected_devices,
+            state.sources.values(),
+            state.default_source.as_deref(),
+        )
+    }
+
+    fn selected_streams<'a>(&self, state: &'a PipeWireState) -> Vec<&'a AudioNode> {
+        state
+            .streams
+            .values()
+            .filter(|stream| {
+                application_matches(&self.settings.selected_applications, &stream.app_names)
+            })
+            .collect()
+    }
+
+    /// Sets the mute switch of nodes, and returns an error if any of them failed.
+    fn set_nodes_muted(nodes: &[&AudioNode], muted: bool) -> Result<(), AudioError> {
+        let mut result = Ok(());
+        for node in nodes {
+            if let Err(err) = set_node_mute(&node.node, muted) {
+                result = Err(AudioError::Failed(format!(
+                    "Failed to set mute on {}: {}",
+                    node.name, err
+                )));
+            }
+        }
+        result
+    }
+}
+impl Mute for PipeWireControl {
+    fn is_muted(&mut self) -> Result<Option<bool>, AudioError> {
+        self.roundtrip()?;
+        let device = match &self.settings.unmute_device {
+            Some(dev) => dev,
+            None => &self.settings.mute_device,
+        };
+        let state = self.state.borrow();
+        let not_found = |message: &str| Err(AudioError::Failed(String::from(message)));
+        let muted = match device {
+            PulseMuteDevice::All => self
+                .all_sources(&state)
+                .iter()
+                .all(|source| source.muted.get() == Some(true)),
+            PulseMuteDevice::Default => match &state.default_source {
+                Some(device_name) => match Self::find_source(&state, device_name) {
+                    Some(source) => source.muted.get() == Some(true),
+                    None => return not_found("Failed to find device with default source name"),
+                },
+                None => return not_found("No default device selected"),
+            },
+            PulseMuteDevice::Selected if !self.settings.selected_devices.is_empty() => {
+                let sources = self.selected_sources(&state);
+                if sources.is_empty() {
+                    return not_found("Failed to find any of the selected devices");
+                }
+                sources
+                    .iter()
+                    .all(|source| source.muted.get() == Some(true))
+            },
+            PulseMuteDevice::Selected => {
+                match Self::find_source(&state, &self.settings.selected_device_name) {
+                    Some(source) => source.muted.get() == Some(true),
+                    None => return not_found("Failed to find device with selected source name"),
+                }
+            },
+            PulseMuteDevice::Applications => {
+                let streams = self.selected_streams(&state);
+                if streams.is_empty() {
+                    // Not an error, the applications may just not be recording.
+                    println!("No recording streams of selected applications found");
+                    return Ok(None);
+                }
+                streams
+                    .iter()
+                    .all(|stream| stream.muted.get() == Some(true))
+            },
+        };
+        Ok(Some(muted))
+    }
+
+    fn set_muted(&mut self, muted: bool) -> Result<(), AudioError> {
+        self.roundtrip()?;
+        let device;
+        if muted {
+            device = &self.settings.mute_device;
+        } else {
+            device = match &self.settings.unmute_device {
+                Some(dev) => dev,
+                None => &self.settings.mute_device,
+            };
+        }
+        let mut result = Ok(());
+        let mut known_streams = None;
+        {
+            let state = self.state.borrow();
+            let selected: Vec<&AudioNode> = match device {
+                PulseMuteDevice::All => self.all_sources(&state),
+                PulseMuteDevice::Default => match &state.default_source {
+                    Some(device_name) => {
+                        Self::find_source(&state, device_name).into_iter().collect()
+                    },
+                    None => Vec::new(),
+                },
+                PulseMuteDevice::Selected if !self.settings.selected_devices.is_empty() => {
+                    self.selected_sources(&state)
+                },
+                PulseMuteDevice::Selected => {
+                    Self::find_source(&state, &self.settings.selected_device_name)
+                        .into_iter()
+                        .collect()
+                },
+                PulseMuteDevice::Applications => self.selected_streams(&state),
+            };
+            let is_applications = matches!(device, PulseMuteDevice::Applications);
+            if selected.is_empty() && !is_applications {
+                result = Err(AudioError::Failed(String::from(
+                    "Failed to find recording device",
+                )));
+            }
+            if is_applications {
+                known_streams = Some(selected.iter().map(|stream| stream.id).collect());
+            }
+            if let Err(err) = Self::set_nodes_muted(&selected, muted) {
+                result = Err(err);
+            }
+        }
+        if let Some(known_streams) = known_streams {
+            self.known_streams = known_streams;
+        }
+        self.roundtrip()?;
+        result
+    }
+
+    fn mute_new_streams(&mut self) -> Result<(), AudioError> {
+        if !matches!(self.settings.mute_device, PulseMuteDevice::Applications) {
+            return Ok(());
+        }
+        self.roundtrip()?;
+        let result;
+        let known_streams;
+        {
+            let state = self.state.borrow();
+            let streams = self.selected_streams(&state);
+            let new_streams: Vec<&AudioNode> = streams
+                .iter()
+                .copied()
+                .filter(|stream| !self.known_streams.contains(&stream.id))
+                .collect();
+            result = Self::set_nodes_muted(&new_streams, true);
+            known_streams = streams.iter().map(|stream| stream.id).collect();
+        }
+        self.known_streams = known_streams;
+        self.roundtrip()?;
+        result
+    }
+}
+
+fn add_global(
+    registry: &Registry,
+    state: &Rc<RefCell<PipeWireState>>,
+    global: &GlobalObject<&DictRef>,
+) {
+    let props = match global.props {
+        Some(props) => props,
+        None => return,
+    };
+    match global.type_ {
+        ObjectType::Node => match props.get("media.class") {
+            Some(SOURCE_MEDIA_CLASS) => {
+                if let Some(source) = bind_node(registry, global, props, Vec::new()) {
+                    state.borrow_mut().sources.insert(global.id, source);
+                }
+            },
+            Some(STREAM_MEDIA_CLASS) => {
+                let app_names = vec![
+                    props.get("application.name").map(String::from),
+                    props.get("application.process.binary").map(String::from),
+                ];
+                if let Some(stream) = bind_node(registry, global, props, app_names) {
+                    state.borrow_mut().streams.insert(global.id, stream);
+                }
+            },
+            _ => {},
+        },
+        ObjectType::Metadata if props.get("metadata.name") == Some("default") => {
+            let metadata: Metadata = match registry.bind(global) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    println!("Failed to bind PipeWire metadata: {}", err);
+                    return;
+                },
+            };
+            let metadata_state = Rc::downgrade(state);
+            let listener = metadata
+                .add_listener_local()
+                .property(move |_subject, key, _type, value| {
+                    if key == Some(DEFAULT_SOURCE_KEY) {
+                        if let Some(state) = metadata_state.upgrade() {
+                            state.borrow_mut().default_source = value.and_then(parse_node_name);
+                        }
+                    }
+                    0
+                })
+                .register();
+            state.borrow_mut().metadata = Some((metadata, listener));
+        },
+        _ => {},
+    }
+}
+
+impl SourceInfo for AudioNode {
+    fn name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn description(&self) -> Option<&str> {
+        self.properties.get("node.description").map(String::as_str)
+    }
+
+    fn property(&self, key: &str) -> Option<String> {
+        self.properties.get(key).cloned()
+    }
+}
+
+/// Binds a node and keeps track of its mute state.
+fn bind_node(
+    registry: &Registry,
+    global: &GlobalObject<&DictRef>,
+    props: &DictRef,
+    app_names: Vec<Option<String>>,
+) -> Option<AudioNode> {
+    let node: Node = match registry.bind(global) {
+        Ok(node) => node,
+        Err(err) => {
+            println!("Failed to bind PipeWire node {}: {}", global.id, err);
+            return None;
+        },
+    };
+    let muted = Rc::new(Cell::new(None));
+    let param_muted = Rc::clone(&muted);
+    let listener = node
+        .add_listener_local()
+        .param(move |_seq, _id, _index, _next, param| {
+            if let Some(mute) = param.and_then(parse_mute) {
+                param_muted.set(Some(mute));
+            }
+        })
+        .register();
+    node.subscribe_params(&[ParamType::Props]);
+    Some(AudioNode {
+        id: global.id,
+        name: props.get("node.name").unwrap_or("").to_string(),
+        properties: props
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        app_names,
+        node,
+        muted,
+        _listener: listener,
+    })
+}
+
+fn parse_mute(param: &Pod) -> Option<bool> {
+    match PodDeserializer::deserialize_any_from(param.as_bytes()) {
+        Ok((_, Value::Object(object))) => object
+            .properties
+            .iter()
+            .find(|property| property.key == SPA_PROP_mute)
+            .and_then(|property| match property.value {
+                Value::Bool(mute) => Some(mute),
+                _ => None,
+            }),
+        _ => None,
+    }
+}
+
+/// Extracts the node name from a metadata value such as `{ "name": "alsa_input.usb" }`.
+fn parse_node_name(value: &str) -> Option<String> {
+    let rest = &value[value.find("\"name\"")? + 6..];
+    let start = rest.find('"')? + 1;
+    let end = start + rest[start..].find('"')?;
+    Some(rest[start..end].to_string())
+}
+
+fn set_node_mute(node: &Node, muted: bool) -> Result<(), String> {
+    let value = Value::Object(Object {
+        type_: SpaTypes::ObjectParamProps.as_raw(),
+        id: ParamType::Props.as_raw(),
+        properties: vec![Property {
+            key: SPA_PROP_mute,
+            flags: PropertyFlags::empty(),
+            value: Value::Bool(muted),
+        }],
+    });
+    let (cursor, _) = PodSerializer::serialize(Cursor::new(Vec::new()), &value)
+        .map_err(|err| format!("{:?}", err))?;
+    let bytes = cursor.into_inner();
+    let param = Pod::from_bytes(&bytes).ok_or(String::from("Invalid pod"))?;
+    node.set_param(ParamType::Props, 0, param);
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use crossbeam_channel::{unbounded, Sender};
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use crate::device::Color;
+use crate::muteme::{ControlMessage, ControlStatus, OperationMode};
+
+/// Commands accepted on the control socket, one per line. Each command is answered with a
+/// single line, which is either `ok`, the requested status, or `error: <message>`.
+#[derive(Debug)]
+pub enum Command {
+    Mute,
+    Unmute,
+    Toggle,
+    Deafen,
+    Undeafen,
+    ToggleDeafen,
+    Status,
+    SetColor(bool, Color),
+    SetMode(OperationMode),
+}
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            ["mute"] => Ok(Self::Mute),
+            ["unmute"] => Ok(Self::Unmute),
+            ["toggle"] => Ok(Self::Toggle),
+            ["deafen"] => Ok(Self::Deafen),
+            ["undeafen"] => Ok(Self::Undeafen),
+            ["toggle-deafen"] => Ok(Self::ToggleDeafen),
+            ["status"] => Ok(Self::Status),
+            ["set-color", state, color] => {
+                let mute_state = match *state {
+                    "muted" => true,
+                    "unmuted" => false,
+                    _ => return Err(format!("Invalid state: {}", state)),
+                };
+                Ok(Self::SetColor(mute_state, color.parse()?))
+            },
+            ["set-mode", mode] => Ok(Self::SetMode(mode.parse()?)),
+            [] => Err(String::from("Empty command")),
+            _ => Err(format!("Invalid command: {}", line.trim())),
+        }
+    }
+}
+
+pub fn default_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("mutebtn.sock"),
+        None => PathBuf::from("/run/mutebtn.sock"),
+    }
+}
+
+/// Listens on the control socket and forwards commands to all control threads. The socket
+/// file is replaced if it is left over from a previous run, but not if another instance is
+/// still listening on it.
+pub fn spawn_server(path: &Path, ctrl_senders: Vec<Sender<ControlMessage>>) -> io::Result<()> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("Socket {} is already in use", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    println!("Listening on {}", path.display());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let client_senders = ctrl_senders.clone();
+                    thread::spawn(move || {
+                        handle_client(stream, &client_senders).unwrap_or(());
+                    });
+                },
+                Err(err) => println!("{}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_client(stream: UnixStream, ctrl_senders: &[Sender<ControlMessage>]) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    for line in reader.lines() {
+        let response = match Command::parse(&line?) {
+            Ok(command) => run_command(command, ctrl_senders),
+            Err(err) => format!("error: {}", err),
+        };
+        writeln!(writer, "{}", response)?;
+    }
+    Ok(())
+}
+
+fn run_command(command: Command, ctrl_senders: &[Sender<ControlMessage>]) -> String {
+    if let Command::Status = command {
+        return match get_status(ctrl_senders) {
+            Some(status) => format!(
+                "muted={} deafened={} mode={}",
+                status.muted, status.deafened, status.operation_mode
+            ),
+            None => String::from("error: No status available"),
+        };
+    }
+    for ctrl_sender in ctrl_senders {
+        let message = match &command {
+            Command::Mute => ControlMessage::SetMuted(true),
+            Command::Unmute => ControlMessage::SetMuted(false),
+            Command::Toggle => ControlMessage::Toggle,
+            Command::Deafen => ControlMessage::SetDeafened(true),
+            Command::Undeafen => ControlMessage::SetDeafened(false),
+            Command::ToggleDeafen => ControlMessage::ToggleDeafen,
+            Command::SetColor(mute_state, color) => ControlMessage::SetColor(*mute_state, *color),
+            Command::SetMode(mode) => ControlMessage::SetMode(mode.clone()),
+            Command::Status => unreachable!(),
+        };
+        ctrl_sender.send(message).unwrap_or(());
+    }
+    String::from("ok")
+}
+
+/// Collects the status from all control threads.
+fn get_status(ctrl_senders: &[Sender<ControlMessage>]) -> Option<ControlStatus> {
+    let mut statuses = Vec::new();
+    for ctrl_sender in ctrl_senders {
+        let (status_sender, status_receiver) = unbounded();
+        ctrl_sender
+            .send(ControlMessage::GetStatus(status_sender))
+            .unwrap_or(());
+        if let Ok(status) = status_receiver.recv_timeout(Duration::from_secs(1)) {
+            statuses.push(status);
+        }
+    }
+    ControlStatus::combine(&statuses)
+}
+
+/// Sends a single command to a running instance and returns its response.
+pub fn send_command(path: &Path, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(response.trim_end().to_string())
+}
@@ -0,0 +1,32 @@
+use std::{cmp::Reverse, collections::BinaryHeap, time::Instant};
+
+/// Pending deadlines of a control thread, which it waits for along with its messages instead
+/// of running a thread per timer.
+#[derive(Default)]
+pub struct Timers {
+    deadlines: BinaryHeap<Reverse<Instant>>,
+}
+
+impl Timers {
+    pub fn schedule(&mut self, deadline: Instant) {
+        self.deadlines.push(Reverse(deadline));
+    }
+
+    /// Returns the earliest pending deadline.
+    pub fn next(&self) -> Option<Instant> {
+        self.deadlines.peek().map(|Reverse(deadline)| *deadline)
+    }
+
+    /// Removes all deadlines that have passed, and returns whether there were any.
+    pub fn take_due(&mut self, now: Instant) -> bool {
+        let mut due = false;
+        while let Some(deadline) = self.next() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop();
+            due = true;
+        }
+        due
+    }
+}
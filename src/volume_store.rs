@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Serializes changes of the audio threads, which read, change and write the whole file.
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Remembers the volumes of devices that are muted by volume, so that they can be restored
+/// after a restart. Each line of the file holds a device name and its channel volumes,
+/// separated by a tab. The file is read again on each change, since several audio threads
+/// may share it, and replaced as a whole, so that a crash does not leave it half written.
+pub struct VolumeStore {
+    path: PathBuf,
+}
+
+impl VolumeStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn default_path() -> PathBuf {
+        if let Some(dir) = env::var_os("XDG_STATE_HOME") {
+            return Path::new(&dir).join("mutebtn").join("volumes");
+        }
+        match env::var_os("HOME") {
+            Some(dir) => Path::new(&dir).join(".local/state/mutebtn/volumes"),
+            None => PathBuf::from("/var/lib/mutebtn/volumes"),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Vec<u32>> {
+        let _lock = FILE_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        self.load().remove(name)
+    }
+
+    /// Saves the volumes of a device, or removes them if `None` is passed.
+    pub fn set(&self, name: &str, volumes: Option<Vec<u32>>) {
+        let _lock = FILE_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let mut saved = self.load();
+        match volumes {
+            Some(volumes) => saved.insert(name.to_string(), volumes),
+            None => saved.remove(name),
+        };
+        if let Err(err) = self.save(&saved) {
+            println!("Could not save volumes to {}: {}", self.path.display(), err);
+        }
+    }
+
+    fn load(&self) -> HashMap<String, Vec<u32>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(_) => return HashMap::new(),
+        };
+        let mut saved = HashMap::new();
+        for line in content.lines() {
+            let mut fields = line.splitn(2, '\t');
+            if let (Some(name), Some(values)) = (fields.next(), fields.next()) {
+                let volumes: Result<Vec<u32>, _> = values.split(',').map(str::parse).collect();
+                if let Ok(volumes) = volumes {
+                    saved.insert(name.to_string(), volumes);
+                }
+            }
+        }
+        saved
+    }
+
+    fn save(&self, saved: &HashMap<String, Vec<u32>>) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&temp_path)?;
+        for (name, volumes) in saved {
+            let values: Vec<String> = volumes.iter().map(u32::to_string).collect();
+            writeln!(file, "{}\t{}", name, values.join(","))?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{process, thread};
+
+    /// Returns a store in a new directory for the test.
+    fn store(test_name: &str) -> VolumeStore {
+        let dir = env::temp_dir().join(format!("mutebtn-{}-{}", process::id(), test_name));
+        fs::remove_dir_all(&dir).unwrap_or(());
+        VolumeStore::new(dir.join("volumes"))
+    }
+
+    #[test]
+    fn saves_and_loads_volumes() {
+        let store = store("saves_and_loads_volumes");
+        assert_eq!(store.get("mic"), None);
+        store.set("mic", Some(vec![100, 200]));
+        store.set("headset", Some(vec![300]));
+        store.set("mic", Some(vec![400, 500]));
+
+        let reopened = VolumeStore::new(store.path.clone());
+        assert_eq!(reopened.get("mic"), Some(vec![400, 500]));
+        assert_eq!(reopened.get("headset"), Some(vec![300]));
+        reopened.set("mic", None);
+        assert_eq!(store.get("mic"), None);
+        assert_eq!(store.get("headset"), Some(vec![300]));
+        assert!(!store.path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let store = store("skips_malformed_lines");
+        fs::create_dir_all(store.path.parent().unwrap()).unwrap();
+        fs::write(
+            &store.path,
+            "mic\t100,200\nno tab\nheadset\t300,x\nempty\t\n\nspeaker.monitor\t7\n",
+        )
+        .unwrap();
+        let mut saved: Vec<(String, Vec<u32>)> = store.load().into_iter().collect();
+        saved.sort();
+        assert_eq!(
+            saved,
+            vec![
+                (String::from("mic"), vec![100, 200]),
+                (String::from("speaker.monitor"), vec![7]),
+            ]
+        );
+        // Saving again keeps the valid entries only.
+        store.set("other", Some(vec![1]));
+        assert_eq!(store.load().len(), 3);
+    }
+
+    #[test]
+    fn keeps_changes_of_concurrent_threads() {
+        let path = store("keeps_changes_of_concurrent_threads").path;
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let store = VolumeStore::new(path.clone());
+                thread::spawn(move || {
+                    for j in 0..10 {
+                        store.set(&format!("device{}", i), Some(vec![j]));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let store = VolumeStore::new(path);
+        for i in 0..8 {
+            assert_eq!(store.get(&format!("device{}", i)), Some(vec![9]));
+        }
+    }
+}